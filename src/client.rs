@@ -1,18 +1,26 @@
-use std::sync::mpsc::{channel, Receiver, Sender};
+use std::io::Write;
+use std::sync::mpsc::{channel, Receiver, RecvError, RecvTimeoutError, Sender};
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 
 use color_eyre::eyre::Result;
-use log::info;
+use log::{debug, error, info};
 
-use crate::cmdline::{self, ClientCommand};
-use crate::common::{self, get_ws_builder, Address, Message, Request, WSMsg};
+use crate::cmdline::{self, ClientCommand, QueueCommand};
+use crate::common::{
+    self, get_ws_builder, Address, EventKind, Hello, Message, PlaybackSpeed, PlaybackState,
+    PlaylistReq, PlaylistResp, QueueListResp, Request, RequestId, Response, SeekReq, StatusResp,
+    SubscribeReq, TrackInfo, VolumeReq, WSMsg,
+};
 use crate::error::{AsEyreErrorResult, DoodleError};
+use crate::{shell, tui};
 
 pub struct Client {
     sender: Arc<Mutex<Option<ws::Sender>>>,
     thread: Option<thread::JoinHandle<()>>,
     recv_channel: Receiver<WSMsg>,
+    next_request_id: u64,
     #[allow(dead_code)]
     inner: ClientInner,
 }
@@ -33,14 +41,20 @@ struct ClientInner {
 
 impl ws::Handler for ClientInner {
     fn on_open(&mut self, _shake: ws::Handshake) -> ws::Result<()> {
-        let _ = self.mailbox.lock().unwrap().send(WSMsg::Open);
+        self.mailbox.lock().unwrap().send(WSMsg::Open);
         Ok(())
     }
 
     fn on_message(&mut self, msg: ws::Message) -> ws::Result<()> {
-        let decoded_msg: Message = serde_json::from_str(&msg.to_string()).unwrap();
-        let _ = self
-            .mailbox
+        let decoded_msg = match common::decode_message(&msg) {
+            Ok(decoded_msg) => decoded_msg,
+            Err(err) => {
+                // The server doesn't expect responses, so there's no telling it
+                error!("Ignoring message from the server: {}", err);
+                return Ok(());
+            }
+        };
+        self.mailbox
             .lock()
             .unwrap()
             .send(WSMsg::Message(decoded_msg));
@@ -48,23 +62,22 @@ impl ws::Handler for ClientInner {
     }
 
     fn on_shutdown(&mut self) {
-        let _ = self.mailbox.lock().unwrap().send(WSMsg::Shutdown);
+        self.mailbox.lock().unwrap().send(WSMsg::Shutdown);
     }
 
     fn on_close(&mut self, code: ws::CloseCode, reason: &str) {
-        let _ = self
-            .mailbox
+        self.mailbox
             .lock()
             .unwrap()
             .send(WSMsg::Close(code, reason.to_owned()));
     }
 
     fn on_error(&mut self, err: ws::Error) {
-        let _ = self.mailbox.lock().unwrap().send(WSMsg::Error(err));
+        self.mailbox.lock().unwrap().send(WSMsg::Error(err));
     }
 
     fn on_timeout(&mut self, _event: ws::util::Token) -> ws::Result<()> {
-        let _ = self.mailbox.lock().unwrap().send(WSMsg::Timeout);
+        self.mailbox.lock().unwrap().send(WSMsg::Timeout);
         Ok(())
     }
 }
@@ -74,6 +87,15 @@ impl Client {
         self.recv_channel.recv().as_eyre_result()
     }
 
+    /// Like `recv`, but gives up with `None` after `timeout`
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<Option<WSMsg>> {
+        match self.recv_channel.recv_timeout(timeout) {
+            Ok(msg) => Ok(Some(msg)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => Err(DoodleError::MpscRecvError(RecvError))?,
+        }
+    }
+
     pub fn send(&self, message: Message) -> Result<()> {
         let sender = self.sender.lock().unwrap();
         match &*sender {
@@ -82,6 +104,38 @@ impl Client {
         }
     }
 
+    /// Introduce ourselves to the server, returning its own introduction.
+    /// Fails if the server can't talk to us.
+    pub fn handshake(&mut self) -> Result<Hello> {
+        self.send(Message::Hello(Hello::local()))?;
+        loop {
+            match self.recv()? {
+                WSMsg::Message(Message::Hello(hello)) if hello.is_compatible() => return Ok(hello),
+                WSMsg::Message(Message::Hello(hello)) => Err(DoodleError::Generic(format!(
+                    "the server on {} ({}) speaks protocol version {}, not {}",
+                    hello.hostname,
+                    hello.version,
+                    hello.protocol_version,
+                    common::PROTOCOL_VERSION
+                )))?,
+                WSMsg::Message(Message::Response {
+                    response: Response::Error(err),
+                    ..
+                }) => Err(DoodleError::FailureResponse(err))?,
+                WSMsg::Message(Message::Event(event)) => debug!("Ignoring {:?}", event),
+                rsp => Err(DoodleError::UnexpectedResponse(Box::new(rsp)))?,
+            }
+        }
+    }
+
+    /// Send a request, returning the id its response will carry
+    pub fn request(&mut self, request: Request) -> Result<RequestId> {
+        let id = RequestId(self.next_request_id);
+        self.next_request_id += 1;
+        self.send(Message::Request { id, request })?;
+        Ok(id)
+    }
+
     pub fn close(&mut self) {
         let sender = self.sender.lock().unwrap();
         match &*sender {
@@ -117,6 +171,7 @@ impl Client {
             sender: sender_arc.clone(),
             thread: None,
             recv_channel: rx,
+            next_request_id: 0,
             inner: ClientInner {
                 mailbox: mailbox.clone(),
             },
@@ -162,34 +217,460 @@ impl Client {
     }
 }
 
-pub fn make_message(command: &cmdline::Client) -> Result<Message> {
-    Ok(Message::Request(match &command.command {
-        ClientCommand::Play(_) => Request::Play(common::PlayReq),
-        ClientCommand::Pause | ClientCommand::Queue(_) | ClientCommand::Status => {
-            Err(DoodleError::Generic("Not Implemented".to_owned()))?
+fn music_selection(music: &cmdline::Music) -> common::Music {
+    match music {
+        cmdline::Music::Song { songs } => common::Music::Songs(songs.clone()),
+        cmdline::Music::Playlist { playlist } => common::Music::Playlist(playlist.clone()),
+        cmdline::Music::AllSongs => common::Music::AllSongs,
+    }
+}
+
+/// Positions start at 1 on the command line, but indexes at 0 in requests
+fn position_index(position: usize) -> Result<usize> {
+    match position.checked_sub(1) {
+        Some(index) => Ok(index),
+        None => Err(DoodleError::Generic("positions start at 1".to_owned()))?,
+    }
+}
+
+fn queue_request(queue: &cmdline::Queue) -> Result<Request> {
+    Ok(match &queue.command {
+        QueueCommand::Music(music) => Request::Queue(common::QueueReq {
+            music: music_selection(music),
+            shuffled: queue.shuffled,
+        }),
+        QueueCommand::List => Request::ListQueue,
+        QueueCommand::Remove { positions } => {
+            Request::RemoveFromQueue(position_index(positions.first)?..positions.last)
+        }
+        QueueCommand::Move { from, to } => Request::MoveInQueue {
+            from: position_index(*from)?,
+            to: position_index(*to)?,
+        },
+        QueueCommand::Clear => Request::ClearQueue,
+        QueueCommand::InsertNext { songs } => Request::InsertNext(common::QueueReq {
+            music: common::Music::Songs(songs.clone()),
+            shuffled: queue.shuffled,
+        }),
+        QueueCommand::Shuffle => Request::ShuffleQueue,
+    })
+}
+
+fn playlist_request(playlist: &cmdline::Playlist) -> Result<PlaylistReq> {
+    Ok(match playlist {
+        cmdline::Playlist::List => PlaylistReq::List,
+        cmdline::Playlist::Show { name } => PlaylistReq::Show(name.clone()),
+        cmdline::Playlist::Create { name } => PlaylistReq::Create(name.clone()),
+        cmdline::Playlist::Add { name, music } => PlaylistReq::Add {
+            name: name.clone(),
+            music: music_selection(music),
+        },
+        cmdline::Playlist::Remove { name, index } => PlaylistReq::Remove {
+            name: name.clone(),
+            index: position_index(*index)?,
+        },
+        cmdline::Playlist::Move { name, from, to } => PlaylistReq::Move {
+            name: name.clone(),
+            from: position_index(*from)?,
+            to: position_index(*to)?,
+        },
+        cmdline::Playlist::SaveQueue { name, replace } => PlaylistReq::SaveQueue {
+            name: name.clone(),
+            replace: *replace,
+        },
+        cmdline::Playlist::Delete { name } => PlaylistReq::Delete(name.clone()),
+    })
+}
+
+/// Format a duration as "m:ss", or "h:mm:ss" when it's long enough
+pub(crate) fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs / 3600 {
+        0 => format!("{}:{:02}", secs / 60, secs % 60),
+        hours => format!("{}:{:02}:{:02}", hours, secs / 60 % 60, secs % 60),
+    }
+}
+
+fn on_off(value: bool) -> &'static str {
+    if value {
+        "on"
+    } else {
+        "off"
+    }
+}
+
+pub(crate) fn format_volume(volume: u8, muted: bool) -> String {
+    if muted {
+        format!("Volume: {}% (muted)", volume)
+    } else {
+        format!("Volume: {}%", volume)
+    }
+}
+
+pub(crate) fn format_speed(speed: &PlaybackSpeed) -> String {
+    if speed.preserve_pitch {
+        format!("Speed: {}x (pitch preserved)", speed.speed)
+    } else {
+        format!("Speed: {}x", speed.speed)
+    }
+}
+
+/// A single line saying what's playing and how far into it
+pub(crate) fn format_now_playing(status: &StatusResp) -> String {
+    let track = match &status.track {
+        Some(track) => track,
+        None => return format!("{:?}", status.state),
+    };
+
+    let mut now_playing = format!("{:?}: {}", status.state, track.display_name());
+    if let Some(position) = status.position {
+        now_playing += &format!(" [{}", format_duration(position));
+        if let Some(duration) = track.duration {
+            now_playing += &format!("/{}", format_duration(duration));
+        }
+        now_playing += "]";
+    }
+    now_playing
+}
+
+pub(crate) fn format_status(status: &StatusResp) -> String {
+    let mut lines = vec![format_now_playing(status)];
+
+    if let Some(track) = &status.track {
+        let tags = &track.tags;
+        if let Some(album) = &tags.album {
+            let mut album_line = format!("Album: {}", album);
+            if let Some(year) = tags.year {
+                album_line += &format!(" ({})", year);
+            }
+            if let Some(track_number) = tags.track_number {
+                album_line += &format!(", track {}", track_number);
+            }
+            lines.push(album_line);
+        }
+        if let Some(genre) = &tags.genre {
+            lines.push(format!("Genre: {}", genre));
+        }
+        lines.push(format!("File: {}", track.path.display()));
+    }
+
+    if status.speed != PlaybackSpeed::default() {
+        lines.push(format_speed(&status.speed));
+    }
+    lines.push(format!(
+        "{}, repeat: {}, shuffle: {}",
+        format_volume(status.volume, status.muted),
+        on_off(status.repeat),
+        on_off(status.shuffled)
+    ));
+    lines.push(match status.queue_position {
+        Some(index) => format!("Queue: track {} of {}", index + 1, status.queue_length),
+        None => format!("Queue: {} tracks", status.queue_length),
+    });
+
+    lines.join("\n")
+}
+
+/// A track's name and duration, to list it
+fn format_track(track: &TrackInfo) -> String {
+    match track.duration {
+        Some(duration) => format!("{} [{}]", track.display_name(), format_duration(duration)),
+        None => track.display_name(),
+    }
+}
+
+/// The tracks in the queue, pointing out the current one
+fn format_queue(queue: &QueueListResp) -> String {
+    if queue.tracks.is_empty() {
+        return "The queue is empty".to_owned();
+    }
+
+    let lines: Vec<_> = queue
+        .tracks
+        .iter()
+        .enumerate()
+        .map(|(index, track)| {
+            let marker = if queue.position == Some(index) {
+                '>'
+            } else {
+                ' '
+            };
+            format!("{} {:>3}. {}", marker, index + 1, format_track(track))
+        })
+        .collect();
+    lines.join("\n")
+}
+
+fn format_playlist(playlist: &PlaylistResp) -> String {
+    let mut lines = vec![format!(
+        "{}: {} tracks",
+        playlist.name,
+        playlist.entries.len()
+    )];
+    for (index, entry) in playlist.entries.iter().enumerate() {
+        let line = match (&entry.track, &entry.problem) {
+            (Some(track), _) => format_track(track),
+            (None, problem) => format!(
+                "{}: {}",
+                entry.title.as_ref().unwrap_or(&entry.location),
+                problem.as_deref().unwrap_or("can't be played")
+            ),
+        };
+        lines.push(format!("{:>4}. {}", index + 1, line));
+    }
+    if !playlist.skipped.is_empty() {
+        lines.push(format!("Skipped {} entries:", playlist.skipped.len()));
+        lines.extend(
+            playlist
+                .skipped
+                .iter()
+                .map(|skipped| format!("  {}", skipped)),
+        );
+    }
+
+    lines.join("\n")
+}
+
+pub(crate) fn show_response(command: &cmdline::Client, response: Response) -> Result<()> {
+    match (&command.command, response) {
+        (ClientCommand::Status(options), Response::Status(status)) => {
+            if options.json {
+                println!("{}", serde_json::to_string_pretty(&status)?);
+            } else {
+                println!("{}", format_status(&status));
+            }
+        }
+        (ClientCommand::Volume(_), Response::Status(status)) => {
+            println!("{}", format_volume(status.volume, status.muted))
+        }
+        (ClientCommand::Speed(_), Response::Status(status)) => {
+            println!("{}", format_speed(&status.speed))
+        }
+        // Commands like next and previous have nothing to show once done
+        (_, Response::Ok) => {}
+        (_, Response::Error(err)) => Err(DoodleError::FailureResponse(err))?,
+        (command, Response::Queued(queued)) => {
+            let verb = match command {
+                ClientCommand::Play(_) => "Playing",
+                _ => "Queued",
+            };
+            println!("{} {} tracks", verb, queued.tracks.len());
+            if !queued.skipped.is_empty() {
+                println!("Skipped {} entries:", queued.skipped.len());
+                for skipped in &queued.skipped {
+                    println!("  {}", skipped);
+                }
+            }
+        }
+        (_, Response::PlaybackState(state)) => println!("{:?}", state),
+        (_, Response::Volume(volume)) => {
+            println!("{}", format_volume(volume.volume, volume.muted))
+        }
+        (_, Response::Speed(speed)) => println!("{}", format_speed(&speed)),
+        (_, Response::Seeked(position)) => println!("Position: {}", format_duration(position)),
+        (_, Response::QueueList(queue)) => println!("{}", format_queue(&queue)),
+        (_, Response::Playlists(playlists)) => {
+            if playlists.is_empty() {
+                println!("No playlists");
+            }
+            for playlist in playlists {
+                println!("{} ({} tracks)", playlist.name, playlist.length);
+            }
+        }
+        (_, Response::Playlist(playlist)) => println!("{}", format_playlist(&playlist)),
+        (_, Response::Rescan(summary)) => println!(
+            "{} added, {} updated, {} removed, {} tracks in the library",
+            summary.added, summary.updated, summary.removed, summary.tracks
+        ),
+        (_, response) => debug!("Ignoring unexpected {:?}", response),
+    }
+
+    Ok(())
+}
+
+pub fn make_request(command: &cmdline::Client) -> Result<Request> {
+    Ok(match &command.command {
+        ClientCommand::Play(play) => Request::Play(common::PlayReq {
+            music: play.command.as_ref().map(music_selection),
+            shuffled: play.shuffled,
+            repeat: play.repeat,
+        }),
+        ClientCommand::Queue(queue) => queue_request(queue)?,
+        ClientCommand::Pause => Request::Pause,
+        ClientCommand::Resume => Request::Resume,
+        ClientCommand::TogglePause => Request::TogglePause,
+        ClientCommand::Next => Request::Next,
+        ClientCommand::Previous => Request::Previous,
+        ClientCommand::PlayIndex(play_index) => {
+            Request::PlayIndex(position_index(play_index.index)?)
         }
+        ClientCommand::Seek(seek) => Request::Seek(match seek.position {
+            cmdline::SeekPosition::To(position) => SeekReq::To(position),
+            cmdline::SeekPosition::Forward(offset) => SeekReq::Forward(offset),
+            cmdline::SeekPosition::Backward(offset) => SeekReq::Backward(offset),
+        }),
+        ClientCommand::Volume(volume) => match volume.level {
+            Some(cmdline::VolumeLevel::To(level)) => Request::SetVolume(VolumeReq::To(level)),
+            Some(cmdline::VolumeLevel::Up(step)) => Request::SetVolume(VolumeReq::Up(step)),
+            Some(cmdline::VolumeLevel::Down(step)) => Request::SetVolume(VolumeReq::Down(step)),
+            None => Request::Status,
+        },
+        ClientCommand::Mute => Request::Mute,
+        ClientCommand::Unmute => Request::Unmute,
+        ClientCommand::Speed(speed) => match speed.speed {
+            Some(value) => Request::SetSpeed(PlaybackSpeed {
+                speed: value,
+                preserve_pitch: speed.preserve_pitch,
+            }),
+            None => Request::Status,
+        },
+        ClientCommand::Playlist(playlist) => Request::Playlist(playlist_request(playlist)?),
+        ClientCommand::Status(_) => Request::Status,
+        ClientCommand::Watch(_) => Request::Subscribe(SubscribeReq {
+            events: WATCHED_EVENTS.to_vec(),
+        }),
+        ClientCommand::Shell | ClientCommand::Tui => Request::Subscribe(SubscribeReq {
+            events: [WATCHED_EVENTS, &[EventKind::LibraryChanged]].concat(),
+        }),
+        ClientCommand::Rescan => Request::Rescan,
         ClientCommand::Shutdown => Request::Shutdown,
-    }))
+    })
+}
+
+/// The events `watch` subscribes to
+const WATCHED_EVENTS: &[EventKind] = &[
+    EventKind::TrackChanged,
+    EventKind::PlaybackStateChanged,
+    EventKind::QueueChanged,
+    EventKind::VolumeChanged,
+    EventKind::MuteChanged,
+    EventKind::SpeedChanged,
+    EventKind::Seeked,
+];
+
+/// How often `watch` redraws the now-playing line while nothing happens
+const WATCH_REDRAW: Duration = Duration::from_secs(1);
+
+/// The status as it should be now, moving the position along
+/// with the time passed since the status was received
+pub(crate) fn status_since(status: &StatusResp, received: Instant) -> StatusResp {
+    let mut status = status.clone();
+    if status.state == PlaybackState::Playing {
+        let duration = status.track.as_ref().and_then(|track| track.duration);
+        status.position = status.position.map(|position| {
+            let position = position + received.elapsed().mul_f32(status.speed.speed);
+            duration.map_or(position, |duration| position.min(duration))
+        });
+    }
+    status
+}
+
+/// Redraw the now-playing line in place
+fn draw_now_playing(status: &StatusResp, received: Instant) -> Result<()> {
+    let status = status_since(status, received);
+    let mut stdout = std::io::stdout();
+    write!(stdout, "\r{}\x1b[K", format_now_playing(&status))?;
+    stdout.flush()?;
+    Ok(())
+}
+
+/// Follow the events the server pushes after subscribing, until the connection
+/// is closed. Ctrl-C closes the connection.
+fn watch(client: &mut Client, options: &cmdline::Watch) -> Result<()> {
+    let sender = client.sender.clone();
+    ctrlc::set_handler(move || {
+        if let Some(sender) = &*sender.lock().unwrap() {
+            if let Err(err) = sender.close(ws::CloseCode::Normal) {
+                error!("error {:?} closing", err);
+            }
+        }
+    })?;
+
+    // The status is refreshed after each event, rather than pieced together from them
+    let mut status_id = (!options.json)
+        .then(|| client.request(Request::Status))
+        .transpose()?;
+    let mut status = None;
+
+    loop {
+        match client.recv_timeout(WATCH_REDRAW)? {
+            None => {}
+            Some(WSMsg::Message(Message::Response { id, response })) if id == status_id => {
+                match response {
+                    Response::Status(new_status) => status = Some((new_status, Instant::now())),
+                    Response::Error(err) => Err(DoodleError::FailureResponse(err))?,
+                    response => debug!("Ignoring unexpected {:?}", response),
+                }
+            }
+            Some(WSMsg::Message(Message::Response { id, .. })) => {
+                debug!("Ignoring response to unrelated {:?}", id)
+            }
+            Some(WSMsg::Message(Message::Event(event))) => {
+                if options.json {
+                    println!("{}", serde_json::to_string(&event)?);
+                } else {
+                    status_id = Some(client.request(Request::Status)?);
+                }
+            }
+            Some(WSMsg::Close(code, reason)) => {
+                info!("Connection closed: {:?} {}", code, reason);
+                break;
+            }
+            Some(rsp) => Err(DoodleError::UnexpectedResponse(Box::new(rsp)))?,
+        }
+
+        if let Some((status, received)) = &status {
+            draw_now_playing(status, *received)?;
+        }
+    }
+
+    if status.is_some() {
+        println!();
+    }
+    Ok(())
 }
 
 pub(crate) fn main(command: cmdline::Client, server_address: Address) -> Result<()> {
     info!("running {:?} with server {}", command, server_address);
 
-    let message = make_message(&command)?;
+    let request = make_request(&command)?;
 
-    let client = Client::new(&server_address)?;
+    let mut client = Client::new(&server_address)?;
     match client.recv()? {
         WSMsg::Open => {}
-        connect_rsp => return Err(DoodleError::NoOpen(connect_rsp))?,
+        connect_rsp => Err(DoodleError::NoOpen(Box::new(connect_rsp)))?,
     }
 
-    client.send(message)?;
+    let hello = client.handshake()?;
+    debug!(
+        "Connected to {} ({}), features {:?}",
+        hello.hostname, hello.version, hello.features
+    );
 
-    let rsp = client.recv()?;
-    if let WSMsg::Message(Message::Response(response)) = rsp {
-        info!("{:#?}", response); // TODO: replace with debug!(...) when we have real handling
-    } else {
-        return Err(DoodleError::UnexpectedResponse(rsp))?;
+    let request_id = client.request(request)?;
+
+    loop {
+        match client.recv()? {
+            // Only one request is in flight, so an error without an id has to be about it
+            WSMsg::Message(Message::Response { id, response })
+                if id.is_none() || id == Some(request_id) =>
+            {
+                show_response(&command, response)?;
+                break;
+            }
+            WSMsg::Message(Message::Response { id, .. }) => {
+                debug!("Ignoring response to unrelated {:?}", id)
+            }
+            WSMsg::Message(Message::Event(event)) => debug!("Ignoring {:?}", event),
+            rsp => Err(DoodleError::UnexpectedResponse(Box::new(rsp)))?,
+        }
+    }
+
+    match &command.command {
+        ClientCommand::Watch(options) => watch(&mut client, options)?,
+        ClientCommand::Shell => shell::main(&mut client)?,
+        ClientCommand::Tui => tui::main(&mut client)?,
+        _ => {}
     }
 
     Ok(())
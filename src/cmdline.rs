@@ -1,4 +1,4 @@
-use std::{path::PathBuf, str::FromStr};
+use std::{path::PathBuf, str::FromStr, time::Duration};
 
 use structopt::{clap::AppSettings, StructOpt};
 
@@ -19,9 +19,11 @@ pub struct Play {
     #[structopt(long)]
     pub shuffled: bool,
 
+    /// Start over once the end of the queue is reached
     #[structopt(long)]
     pub repeat: bool,
 
+    /// What to play, the current queue is played again from the start if not given
     #[structopt(subcommand)]
     pub command: Option<Music>,
 }
@@ -32,12 +34,154 @@ pub struct Queue {
     pub shuffled: bool,
 
     #[structopt(subcommand)]
-    pub command: Music,
+    pub command: QueueCommand,
+}
+
+#[derive(Debug, StructOpt)]
+pub enum QueueCommand {
+    #[structopt(flatten)]
+    Music(Music),
+
+    /// Show the songs in the queue
+    List,
+
+    /// Remove songs from the queue
+    Remove {
+        /// A position in the queue starting at 1, or a range of them like 3-5
+        positions: Positions,
+    },
+
+    /// Move a song to another position in the queue
+    Move {
+        /// Position of the song to move, starting at 1
+        from: usize,
+
+        /// Position the song should end up at
+        to: usize,
+    },
+
+    /// Remove every song from the queue but the one playing
+    Clear,
+
+    /// Queue songs to play right after the current one
+    InsertNext {
+        #[structopt(required = true, min_values = 1)]
+        songs: Vec<String>,
+    },
+
+    /// Shuffle the songs still to be played
+    Shuffle,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct PlayIndex {
+    /// Position in the queue, starting at 1
+    pub index: usize,
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(setting = AppSettings::AllowLeadingHyphen)]
+pub struct Seek {
+    /// A position like 1:23 or 83s, or an offset from the current position like +10s or -1:00
+    pub position: SeekPosition,
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(setting = AppSettings::AllowLeadingHyphen)]
+pub struct Volume {
+    /// A percentage from 0 to 100, or a change to the current one like +10 or -10.
+    /// Shows the current volume if not given.
+    pub level: Option<VolumeLevel>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Speed {
+    /// How many times faster than normal to play, like 1.5 or 0.75x.
+    /// Shows the current speed if not given.
+    #[structopt(parse(try_from_str = parse_speed))]
+    pub speed: Option<f32>,
+
+    /// Keep voices sounding natural rather than higher or lower
+    #[structopt(long)]
+    pub preserve_pitch: bool,
+}
+
+fn parse_speed(s: &str) -> Result<f32, &'static str> {
+    s.trim_end_matches('x')
+        .parse()
+        .ok()
+        .filter(|speed: &f32| speed.is_finite() && *speed > 0.0)
+        .ok_or("expected a speed like 1.5 or 0.75x")
+}
+
+#[derive(Debug, StructOpt)]
+pub enum Playlist {
+    /// List the playlists kept by the server
+    List,
+
+    /// Show the songs in a playlist
+    Show { name: String },
+
+    /// Start a new empty playlist
+    Create { name: String },
+
+    /// Add songs to the end of a playlist
+    Add {
+        name: String,
+
+        #[structopt(subcommand)]
+        music: Music,
+    },
+
+    /// Remove a song from a playlist
+    Remove {
+        name: String,
+
+        /// Position in the playlist, starting at 1
+        index: usize,
+    },
+
+    /// Move a song to another position in a playlist
+    Move {
+        name: String,
+
+        /// Position of the song to move, starting at 1
+        from: usize,
+
+        /// Position the song should end up at
+        to: usize,
+    },
+
+    /// Make a playlist of the whole queue
+    SaveQueue {
+        name: String,
+
+        /// Overwrite the playlist if it already exists
+        #[structopt(long)]
+        replace: bool,
+    },
+
+    /// Delete a playlist
+    Delete { name: String },
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Status {
+    /// Print the raw status as JSON
+    #[structopt(long)]
+    pub json: bool,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Watch {
+    /// Print each event as a JSON object instead of a now-playing line
+    #[structopt(long)]
+    pub json: bool,
 }
 
 #[derive(Debug, StructOpt)]
 pub enum ClientCommand {
-    /// TODO: add docs
+    /// Replace the music queue and start playing it
     Play(Play),
 
     /// Add to music queue
@@ -46,8 +190,54 @@ pub enum ClientCommand {
     /// Pause currently playing music
     Pause,
 
+    /// Resume paused music
+    Resume,
+
+    /// Pause or resume the music
+    TogglePause,
+
+    /// Skip to the next song in the queue
+    Next,
+
+    /// Go back to the previous song, or to the start of the current one
+    Previous,
+
+    /// Jump to a song in the queue
+    PlayIndex(PlayIndex),
+
+    /// Move playback within the current song
+    Seek(Seek),
+
+    /// Show or change the volume
+    Volume(Volume),
+
+    /// Silence the music, keeping the volume for when it's unmuted
+    Mute,
+
+    /// Bring the music back at the volume it had before muting
+    Unmute,
+
+    /// Show or change the playback speed
+    Speed(Speed),
+
+    /// Manage the playlists kept by the server
+    Playlist(Playlist),
+
     /// Query the server for the currently playing song
-    Status,
+    Status(Status),
+
+    /// Keep showing what the server is playing, until interrupted
+    Watch(Watch),
+
+    /// Run commands interactively over a single connection
+    Shell,
+
+    /// Browse the library and control playback in a full-screen terminal UI.
+    /// Logs only go to the logfile while it runs.
+    Tui,
+
+    /// Look for new, changed and deleted files in the server's music library
+    Rescan,
 
     /// Tell the server to exit
     Shutdown,
@@ -63,6 +253,26 @@ pub struct Client {
 pub struct Server {
     /// Music library path
     pub path: PathBuf,
+
+    /// Where to keep the library index between runs.
+    /// Defaults to library.json in the user's cache directory, e.g. ~/.cache/musical-doodle.
+    #[structopt(long)]
+    pub library_cache: Option<PathBuf>,
+
+    /// Where to keep settings such as the volume between runs.
+    /// Defaults to settings.json in the user's config directory, e.g. ~/.config/musical-doodle.
+    #[structopt(long)]
+    pub settings: Option<PathBuf>,
+
+    /// Where to keep the playlists made with the client's playlist commands.
+    /// Defaults to a Playlists directory in the user's data directory, e.g. ~/.local/share/musical-doodle.
+    #[structopt(long)]
+    pub playlists: Option<PathBuf>,
+
+    /// Where to play the audio.
+    /// The valid values are: default, device:<name>, null, wav:<path>.
+    #[structopt(long, default_value = "default")]
+    pub output: OutputKind,
 }
 
 #[derive(Debug, StructOpt)]
@@ -71,6 +281,145 @@ pub enum Command {
     Server(Server),
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OutputKind {
+    /// The system's default output device
+    Default,
+    /// An output device, by name
+    Device(String),
+    /// Play silently, only advancing time
+    Null,
+    /// Render the played audio into a WAV file
+    Wav(PathBuf),
+}
+
+impl FromStr for OutputKind {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "default" => Ok(Self::Default),
+            None if s == "null" => Ok(Self::Null),
+            Some(("device", name)) if !name.is_empty() => Ok(Self::Device(name.to_owned())),
+            Some(("wav", path)) if !path.is_empty() => Ok(Self::Wav(path.into())),
+            _ => Err("valid values: default, device:<name>, null, wav:<path>"),
+        }
+    }
+}
+
+impl std::fmt::Display for OutputKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Default => write!(f, "default"),
+            Self::Device(name) => write!(f, "device:{}", name),
+            Self::Null => write!(f, "null"),
+            Self::Wav(path) => write!(f, "wav:{}", path.display()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeekPosition {
+    To(Duration),
+    Forward(Duration),
+    Backward(Duration),
+}
+
+/// Parse "1:23", "1:02:03", "83", "83s", "2m" or "1h", with fractional seconds allowed
+fn parse_time(s: &str) -> Option<Duration> {
+    let secs = if s.contains(':') {
+        // The seconds, and the minutes when there are hours, have to be below 60
+        let mut parts = s.rsplit(':');
+        let secs = parse_number(parts.next()?)?;
+        let mins = parse_whole(parts.next()?)?;
+        let hours = match parts.next() {
+            Some(hours) if mins < 60 => parse_whole(hours)?,
+            Some(_) => return None,
+            None => 0,
+        };
+        if secs >= 60.0 || parts.next().is_some() {
+            return None;
+        }
+        hours as f64 * 3600.0 + mins as f64 * 60.0 + secs
+    } else {
+        let (number, unit) = match s.char_indices().last()? {
+            (index, 's') => (&s[..index], 1.0),
+            (index, 'm') => (&s[..index], 60.0),
+            (index, 'h') => (&s[..index], 3600.0),
+            _ => (s, 1.0),
+        };
+        parse_number(number)? * unit
+    };
+    Duration::try_from_secs_f64(secs).ok()
+}
+
+/// Parse digits with an optional fraction, without the signs and exponents `f64` allows
+fn parse_number(s: &str) -> Option<f64> {
+    s.chars().all(|c| c.is_ascii_digit() || c == '.').then(|| s.parse().ok())?
+}
+
+/// Parse digits only, without the sign `u64` allows
+fn parse_whole(s: &str) -> Option<u64> {
+    s.chars().all(|c| c.is_ascii_digit()).then(|| s.parse().ok())?
+}
+
+impl FromStr for SeekPosition {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let position = if let Some(offset) = s.strip_prefix('+') {
+            parse_time(offset).map(Self::Forward)
+        } else if let Some(offset) = s.strip_prefix('-') {
+            parse_time(offset).map(Self::Backward)
+        } else {
+            parse_time(s).map(Self::To)
+        };
+        position.ok_or("expected a position like 1:23 or 83s, or an offset like +10s or -30s")
+    }
+}
+
+/// Positions in a list, starting at 1, from `first` to `last` included
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Positions {
+    pub first: usize,
+    pub last: usize,
+}
+
+impl FromStr for Positions {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (first, last) = s.split_once('-').unwrap_or((s, s));
+        match (first.trim().parse(), last.trim().parse()) {
+            (Ok(first), Ok(last)) if 0 < first && first <= last => Ok(Self { first, last }),
+            _ => Err("expected a position like 3 or a range like 3-5, starting at 1"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VolumeLevel {
+    To(u8),
+    Up(u8),
+    Down(u8),
+}
+
+impl FromStr for VolumeLevel {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let percent = |s: &str| s.trim_end_matches('%').parse().ok().filter(|p| *p <= 100);
+        let level = if let Some(step) = s.strip_prefix('+') {
+            percent(step).map(Self::Up)
+        } else if let Some(step) = s.strip_prefix('-') {
+            percent(step).map(Self::Down)
+        } else {
+            percent(s).map(Self::To)
+        };
+        level.ok_or("expected a percentage from 0 to 100, optionally preceded by + or -")
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum LogLevel {
     Debug,
@@ -167,3 +516,50 @@ pub struct Opt {
     #[structopt(subcommand)]
     pub command: Command,
 }
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    fn secs(secs: f64) -> Option<Duration> {
+        Some(Duration::from_secs_f64(secs))
+    }
+
+    #[test]
+    fn times() {
+        assert_eq!(parse_time("83"), secs(83.0));
+        assert_eq!(parse_time("83s"), secs(83.0));
+        assert_eq!(parse_time("2.5s"), secs(2.5));
+        assert_eq!(parse_time("2m"), secs(120.0));
+        assert_eq!(parse_time("1h"), secs(3600.0));
+        assert_eq!(parse_time("1:23"), secs(83.0));
+        assert_eq!(parse_time("0:05.5"), secs(5.5));
+        assert_eq!(parse_time("90:00"), secs(5400.0));
+        assert_eq!(parse_time("1:02:03"), secs(3723.0));
+    }
+
+    #[test]
+    fn bad_times() {
+        for time in [
+            "", "s", ":", "1:", ":5", "1:-5", "-1:05", "1:+5", "+5", "-5", "1:60", "1:75:00",
+            "1:2:3:4", "1.5:00", "1e3", "inf", "NaN", "1x", "-1s",
+        ] {
+            assert_eq!(parse_time(time), None, "parsing {:?}", time);
+        }
+    }
+
+    #[test]
+    fn seek_positions() {
+        let parse = |s: &str| s.parse::<SeekPosition>().ok();
+        assert_eq!(parse("1:23"), Some(SeekPosition::To(Duration::from_secs(83))));
+        assert_eq!(parse("83"), Some(SeekPosition::To(Duration::from_secs(83))));
+        assert_eq!(parse("+10s"), Some(SeekPosition::Forward(Duration::from_secs(10))));
+        assert_eq!(parse("-30s"), Some(SeekPosition::Backward(Duration::from_secs(30))));
+        assert_eq!(parse("-1:00"), Some(SeekPosition::Backward(Duration::from_secs(60))));
+        assert_eq!(parse("--30s"), None);
+        assert_eq!(parse("+-30s"), None);
+        assert_eq!(parse("1:-5"), None);
+    }
+}
@@ -1,35 +1,457 @@
 use std::fmt::Display;
+use std::fs::{self, File};
+use std::io::Write;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use color_eyre::eyre::Result;
 use serde_derive::{Deserialize, Serialize};
 
 use crate::error::AsEyreErrorResult;
+use crate::library::Tags;
+
+/// Bumped whenever the messages change in a way older builds can't understand
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// The directory the server keeps its files in, within the user's cache, config and data directories
+pub const APP_DIR_NAME: &str = "musical-doodle";
+
+/// What this build supports on top of the basic requests, announced in `Hello`
+pub const FEATURES: &[&str] = &[
+    "events",
+    "edit-queue",
+    "library",
+    "list-queue",
+    "manage-playlists",
+    "playlists",
+    "queue",
+    "seek",
+    "skip",
+    "speed",
+    "status",
+    "volume",
+];
+
+/// The first message each side sends after connecting.
+///
+/// Its layout has to stay the same across protocol versions,
+/// so that the peers can always tell whether they can talk at all.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Hello {
+    pub protocol_version: u32,
+    /// The full build version, as logged on startup
+    pub version: String,
+    pub hostname: String,
+    pub features: Vec<String>,
+}
+
+impl Hello {
+    /// Describe this side of the connection
+    pub fn local() -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            version: crate::get_version().to_owned(),
+            hostname: hostname::get()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_else(|_| "<unknown>".to_owned()),
+            features: FEATURES.iter().map(|feature| feature.to_string()).collect(),
+        }
+    }
+
+    pub fn is_compatible(&self) -> bool {
+        self.protocol_version == PROTOCOL_VERSION
+    }
+}
+
+/// A selection of music from the server's library
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Music {
+    /// Songs by path, name or tags, as understood by the server's library search
+    Songs(Vec<String>),
+    Playlist(String),
+    AllSongs,
+}
+
+/// A track in the server's library
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrackInfo {
+    /// Path relative to the server's library path
+    pub path: PathBuf,
+    pub duration: Option<Duration>,
+    pub tags: Tags,
+}
+
+impl TrackInfo {
+    pub fn display_name(&self) -> String {
+        self.tags.display_name(&self.path)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PlayReq {
+    /// What to play instead of the current queue.
+    /// `None` plays the current queue again from the start.
+    pub music: Option<Music>,
+    pub shuffled: bool,
+    /// Start over once the end of the queue is reached
+    pub repeat: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct QueueReq {
+    pub music: Music,
+    pub shuffled: bool,
+}
+
+/// Changes to the playlists kept by the server, which are named by their file names.
+/// Indexes start at 0.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum PlaylistReq {
+    List,
+    Show(String),
+    /// Start a new empty playlist
+    Create(String),
+    Add {
+        name: String,
+        music: Music,
+    },
+    Remove {
+        name: String,
+        index: usize,
+    },
+    /// Move the entry at `from` so that it ends up at `to`
+    Move {
+        name: String,
+        from: usize,
+        to: usize,
+    },
+    /// Make a playlist of the whole queue
+    SaveQueue {
+        name: String,
+        /// Overwrite the playlist if it already exists
+        replace: bool,
+    },
+    Delete(String),
+}
+
+/// Where to move playback to within the current track
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum SeekReq {
+    To(Duration),
+    Forward(Duration),
+    Backward(Duration),
+}
+
+/// A new volume percentage, or a change to the current one
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum VolumeReq {
+    To(u8),
+    Up(u8),
+    Down(u8),
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct VolumeResp {
+    /// Volume percentage, kept while muted
+    pub volume: u8,
+    pub muted: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct PlaybackSpeed {
+    /// How many times faster than normal tracks play
+    pub speed: f32,
+    /// Stretch the tracks in time rather than playing them faster, as a tape would
+    pub preserve_pitch: bool,
+}
+
+impl Default for PlaybackSpeed {
+    fn default() -> Self {
+        Self {
+            speed: 1.0,
+            preserve_pitch: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueuedResp {
+    pub tracks: Vec<TrackInfo>,
+    /// Why parts of the selection were left out, e.g. broken playlist entries
+    pub skipped: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlaylistInfo {
+    pub name: String,
+    /// The number of entries, including the ones that can't be played
+    pub length: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlaylistEntry {
+    /// Where the playlist says the track is, as written
+    pub location: String,
+    pub title: Option<String>,
+    /// The track in the library the entry points to
+    pub track: Option<TrackInfo>,
+    /// Why the entry can't be played, when it can't
+    pub problem: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlaylistResp {
+    pub name: String,
+    pub entries: Vec<PlaylistEntry>,
+    /// Why parts of the music to add were left out, as in `QueuedResp`
+    pub skipped: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PlaybackState {
+    Playing,
+    Paused,
+    Stopped,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StatusResp {
+    pub state: PlaybackState,
+    pub track: Option<TrackInfo>,
+    /// How far into the current track playback is
+    pub position: Option<Duration>,
+    /// Volume percentage, 100 being the track's own volume
+    pub volume: u8,
+    #[serde(default)]
+    pub muted: bool,
+    #[serde(default)]
+    pub speed: PlaybackSpeed,
+    pub repeat: bool,
+    pub shuffled: bool,
+    /// The index of the current track in the queue
+    pub queue_position: Option<usize>,
+    pub queue_length: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueueListResp {
+    /// Every track in the queue, including the ones already played
+    pub tracks: Vec<TrackInfo>,
+    /// The index of the current track
+    pub position: Option<usize>,
+}
+
+/// What changed in the library after a rescan
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct RescanResp {
+    pub added: usize,
+    pub updated: usize,
+    pub removed: usize,
+    /// The number of tracks in the library after the rescan
+    pub tracks: usize,
+}
+
+impl RescanResp {
+    pub fn changed(&self) -> bool {
+        self.added + self.updated + self.removed > 0
+    }
+}
+
+/// The kinds of events a client can subscribe to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum EventKind {
+    LibraryChanged,
+    TrackChanged,
+    PlaybackStateChanged,
+    QueueChanged,
+    VolumeChanged,
+    MuteChanged,
+    SpeedChanged,
+    Seeked,
+}
 
 #[derive(Debug, Serialize, Deserialize)]
-pub struct PlayReq;
+pub struct SubscribeReq {
+    /// The events to receive from now on, replacing any earlier subscription
+    pub events: Vec<EventKind>,
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Request {
     Play(PlayReq),
+    Queue(QueueReq),
+    Pause,
+    Resume,
+    TogglePause,
+    Next,
+    /// Go to the previous track, or restart the current one if it's been playing for a while
+    Previous,
+    /// Play the track at this index in the queue, continuing from there
+    PlayIndex(usize),
+    Seek(SeekReq),
+    SetVolume(VolumeReq),
+    Mute,
+    Unmute,
+    ToggleMute,
+    SetSpeed(PlaybackSpeed),
+    Status,
+    /// List every track in the library
+    Library,
+    ListQueue,
+    /// Remove the tracks at these indexes from the queue
+    RemoveFromQueue(Range<usize>),
+    /// Move the track at `from` in the queue so that it ends up at `to`
+    MoveInQueue {
+        from: usize,
+        to: usize,
+    },
+    /// Remove every track from the queue but the current one
+    ClearQueue,
+    /// Queue music to play right after the current track
+    InsertNext(QueueReq),
+    /// Shuffle the tracks still to be played
+    ShuffleQueue,
+    Playlist(PlaylistReq),
+    Rescan,
+    Subscribe(SubscribeReq),
     Shutdown,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ErrorCode {
+    /// The peer speaks a different protocol version
+    Incompatible,
+    /// The message couldn't be decoded
+    Malformed,
+    /// Some of the requested music isn't in the library
+    NotFound,
+    /// The request can't be carried out in the server's current state
+    InvalidRequest,
+    /// The server doesn't support what was asked of it
+    Unsupported,
+    /// Something went wrong on the server's side
+    Internal,
+}
+
+/// Why the server couldn't fulfil a request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Error {
+    pub code: ErrorCode,
+    pub message: String,
+    /// More specific problems, e.g. each song that wasn't found
+    pub details: Vec<String>,
+}
+
+impl Error {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            details: vec![],
+        }
+    }
+
+    pub fn with_details(mut self, details: Vec<String>) -> Self {
+        self.details = details;
+        self
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}: {}", self.code, self.message)?;
+        for detail in &self.details {
+            write!(f, "\n  {}", detail)?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Response {
     Ok,
+    Error(Error),
+    /// What was added to the queue, or replaced it when playing
+    Queued(QueuedResp),
+    PlaybackState(PlaybackState),
+    /// Where in the current track playback resumed after a seek
+    Seeked(Duration),
+    Volume(VolumeResp),
+    Speed(PlaybackSpeed),
+    Status(StatusResp),
+    Library(Vec<TrackInfo>),
+    QueueList(QueueListResp),
+    Playlists(Vec<PlaylistInfo>),
+    /// A playlist's entries, after any change made to them
+    Playlist(PlaylistResp),
+    Rescan(RescanResp),
 }
 
+/// Notifications the server pushes to subscribed clients without being asked
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Event {
+    LibraryChanged(RescanResp),
+    /// A track started playing, or `None` once playback stopped
+    TrackChanged(Option<TrackInfo>),
+    PlaybackStateChanged(PlaybackState),
+    /// Tracks were added to, removed from or reordered in the queue
+    QueueChanged {
+        length: usize,
+        position: Option<usize>,
+    },
+    /// Volume percentage, as in `StatusResp`
+    VolumeChanged(u8),
+    MuteChanged(bool),
+    SpeedChanged(PlaybackSpeed),
+    /// Playback moved within the current track, to this position
+    Seeked(Duration),
+}
+
+impl Event {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            Self::LibraryChanged(..) => EventKind::LibraryChanged,
+            Self::TrackChanged(..) => EventKind::TrackChanged,
+            Self::PlaybackStateChanged(..) => EventKind::PlaybackStateChanged,
+            Self::QueueChanged { .. } => EventKind::QueueChanged,
+            Self::VolumeChanged(..) => EventKind::VolumeChanged,
+            Self::MuteChanged(..) => EventKind::MuteChanged,
+            Self::SpeedChanged(..) => EventKind::SpeedChanged,
+            Self::Seeked(..) => EventKind::Seeked,
+        }
+    }
+}
+
+/// Chosen by the client for each request, and echoed in the response to it
+#[derive(Debug, Eq, PartialEq, Hash, Copy, Clone, Ord, PartialOrd, Serialize, Deserialize)]
+pub struct RequestId(pub u64);
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Message {
-    Request(Request),
-    Response(Response),
+    Hello(Hello),
+    Request {
+        id: RequestId,
+        request: Request,
+    },
+    /// The response to the request with the same id.
+    /// The id is `None` when the request couldn't even be decoded enough to find it.
+    Response {
+        id: Option<RequestId>,
+        response: Response,
+    },
+    Event(Event),
 }
 
-pub struct ServerRequest(pub Request, pub ConnId, pub ws::Sender);
+pub struct ServerRequest(pub RequestId, pub Request, pub ConnId, pub ws::Sender);
 
 impl std::fmt::Debug for ServerRequest {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "ServerRequest({:?}, {:?}, ws::Sender {{ .. }})", self.0, self.1)
+        write!(
+            f,
+            "ServerRequest({:?}, {:?}, {:?}, ws::Sender {{ .. }})",
+            self.0, self.1, self.2
+        )
     }
 }
 
@@ -82,9 +504,128 @@ pub(crate) fn get_ws_builder(max_connections: usize) -> ws::Builder {
     builder
 }
 
+/// Decode a message received from the peer
+pub(crate) fn decode_message(msg: &ws::Message) -> std::result::Result<Message, Error> {
+    match msg {
+        ws::Message::Text(text) => serde_json::from_str(text).map_err(|err| {
+            Error::new(
+                ErrorCode::Malformed,
+                format!("can't decode message: {}", err),
+            )
+        }),
+        ws::Message::Binary(data) => Err(Error::new(
+            ErrorCode::Malformed,
+            format!("unexpected binary message of {} bytes", data.len()),
+        )),
+    }
+}
+
+/// Dig the id out of a request that can't be decoded as a whole,
+/// e.g. a well formed request of a kind this side doesn't know about
+pub(crate) fn peek_request_id(msg: &ws::Message) -> Option<RequestId> {
+    let value: serde_json::Value = serde_json::from_slice(&msg.clone().into_data()).ok()?;
+    serde_json::from_value(value.get("Request")?.get("id")?.clone()).ok()
+}
+
+/// Replace the contents of the file at `path` with `bytes`, creating its directory if needed.
+/// They're written to the side and renamed over the file,
+/// so a crash never leaves a truncated file behind.
+pub(crate) fn atomic_write(path: &Path, bytes: &[u8]) -> Result<()> {
+    if let Some(directory) = path.parent() {
+        fs::create_dir_all(directory)?;
+    }
+
+    // Named after the whole file name and the process, so no other save can share it
+    let mut temp_name = path.file_name().unwrap_or_default().to_owned();
+    temp_name.push(format!(".{}.tmp", std::process::id()));
+    let temp_path = path.with_file_name(temp_name);
+    let mut file = File::create(&temp_path)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    fs::rename(temp_path, path)?;
+
+    Ok(())
+}
+
 pub(crate) fn send_json_message(message: &Message, sender: &ws::Sender) -> Result<()> {
     let serialized = serde_json::to_string(&message).unwrap_or_else(|e| {
         panic!("to_string failed on \"{}\" with {:?} as input", e, message);
     });
     sender.send(serialized).as_eyre_result()
 }
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn garbage_is_malformed() {
+        for garbage in ["", "not json", "{\"Request\":", "[1, 2, 3]", "{\"Nonsense\": {}}"] {
+            let err = decode_message(&ws::Message::text(garbage)).unwrap_err();
+            assert_eq!(err.code, ErrorCode::Malformed, "decoding {:?}", garbage);
+        }
+    }
+
+    #[test]
+    fn binary_frames_are_malformed() {
+        let err = decode_message(&ws::Message::binary(vec![0, 159, 146, 150])).unwrap_err();
+        assert_eq!(err.code, ErrorCode::Malformed);
+        assert_eq!(err.message, "unexpected binary message of 4 bytes");
+    }
+
+    #[test]
+    fn unknown_request_keeps_its_id() {
+        let msg = ws::Message::text(r#"{"Request": {"id": 42, "request": {"Teleport": "Mars"}}}"#);
+        let err = decode_message(&msg).unwrap_err();
+        assert_eq!(err.code, ErrorCode::Malformed);
+        assert_eq!(peek_request_id(&msg), Some(RequestId(42)));
+    }
+
+    #[test]
+    fn no_id_to_peek_without_a_request() {
+        for text in ["not json", r#"{"Request": {"request": "Status"}}"#, r#"{"Hello": {}}"#] {
+            assert_eq!(peek_request_id(&ws::Message::text(text)), None, "peeking {:?}", text);
+        }
+    }
+
+    #[test]
+    fn atomic_writes_only_leave_their_files() {
+        let directory = std::env::temp_dir().join(format!("doodle-write-{}", std::process::id()));
+        let json = directory.join("nested").join("file.json");
+        let text = directory.join("nested").join("file.txt");
+
+        atomic_write(&json, b"{}").unwrap();
+        atomic_write(&text, b"old").unwrap();
+        atomic_write(&text, b"new").unwrap();
+
+        assert_eq!(fs::read(&json).unwrap(), b"{}");
+        assert_eq!(fs::read(&text).unwrap(), b"new");
+        let mut names: Vec<_> = fs::read_dir(directory.join("nested")).unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["file.json", "file.txt"]);
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn good_messages_round_trip() {
+        let message = Message::Request {
+            id: RequestId(7),
+            request: Request::Seek(SeekReq::Forward(Duration::from_secs(10))),
+        };
+        let text = serde_json::to_string(&message).unwrap();
+
+        let decoded = decode_message(&ws::Message::text(text.clone())).unwrap();
+        assert!(matches!(
+            decoded,
+            Message::Request {
+                id: RequestId(7),
+                request: Request::Seek(SeekReq::Forward(offset)),
+            } if offset == Duration::from_secs(10)
+        ));
+        assert_eq!(serde_json::to_string(&decoded).unwrap(), text);
+    }
+}
@@ -1,7 +1,7 @@
 use std::fmt::Display;
 use std::sync::mpsc::{RecvError, SendError};
 
-use crate::common::{WSMsg, ServerRequest};
+use crate::common::{self, WSMsg, ServerRequest};
 
 #[derive(Debug, thiserror::Error)]
 pub enum DoodleError {
@@ -9,11 +9,11 @@ pub enum DoodleError {
     JsonError(serde_json::Error),
     MpscRecvError(RecvError),
     MpscSendError(SendError<ServerRequest>),
-    NoOpen(WSMsg),
-    SocketError(ws::Error),
-    UnexpectedResponse(WSMsg),
+    NoOpen(Box<WSMsg>),
+    SocketError(Box<ws::Error>),
+    UnexpectedResponse(Box<WSMsg>),
     UrlError(url::ParseError),
-    // FailureResponse(common::Error),  // TODO: add error
+    FailureResponse(common::Error),
     // FaultStatus,
     // FailedStatus,
     Generic(String),
@@ -21,7 +21,10 @@ pub enum DoodleError {
 
 impl Display for DoodleError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        std::fmt::Debug::fmt(self, f)
+        match self {
+            Self::FailureResponse(err) => write!(f, "The server failed the request - {}", err),
+            _ => std::fmt::Debug::fmt(self, f),
+        }
     }
 }
 
@@ -51,7 +54,13 @@ impl From<SendError<ServerRequest>> for DoodleError {
 
 impl From<ws::Error> for DoodleError {
     fn from(v: ws::Error) -> Self {
-        Self::SocketError(v)
+        Self::SocketError(Box::new(v))
+    }
+}
+
+impl From<common::Error> for DoodleError {
+    fn from(v: common::Error) -> Self {
+        Self::FailureResponse(v)
     }
 }
 
@@ -61,12 +70,14 @@ impl From<url::ParseError> for DoodleError {
     }
 }
 
+#[allow(clippy::wrong_self_convention)]
 pub trait AsDoodleErrorResult {
     type OkType;
 
     fn as_doodle_result(self) -> core::result::Result<Self::OkType, DoodleError>;
 }
 
+#[allow(clippy::wrong_self_convention)]
 pub trait AsEyreErrorResult {
     type OkType;
 
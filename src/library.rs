@@ -0,0 +1,820 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::{Duration, Instant, SystemTime};
+
+use color_eyre::eyre::Result;
+use log::{debug, info, warn};
+use notify::{EventKind, RecursiveMode, Watcher as _};
+use serde_derive::{Deserialize, Serialize};
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::{MetadataOptions, MetadataRevision, StandardTagKey};
+use symphonia::core::probe::Hint;
+use unicase::UniCase;
+
+use crate::common::{self, RescanResp, TrackInfo};
+use crate::error::{AsEyreErrorResult, DoodleError};
+use crate::playlist::PlaylistFormat;
+
+/// Where the library index is cached by default, in the user's cache directory
+pub fn default_cache_path() -> Option<PathBuf> {
+    Some(
+        dirs::cache_dir()?
+            .join(common::APP_DIR_NAME)
+            .join("library.json"),
+    )
+}
+
+/// How long the library has to be left alone before changes to it are picked up,
+/// so files that are still being copied in aren't read half-way
+const WATCH_SETTLE: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Format {
+    Flac,
+    M4a,
+    Mp3,
+    Ogg,
+    Wav,
+}
+
+impl Format {
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let extension = UniCase::new(path.extension()?.to_str()?);
+        [
+            ("flac", Self::Flac),
+            ("m4a", Self::M4a),
+            ("mp4", Self::M4a),
+            ("mp3", Self::Mp3),
+            ("ogg", Self::Ogg),
+            ("oga", Self::Ogg),
+            ("wav", Self::Wav),
+        ]
+        .into_iter()
+        .find_map(|(ext, format)| (UniCase::new(ext) == extension).then_some(format))
+    }
+}
+
+/// Metadata read from the tags embedded in a track
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Tags {
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub title: Option<String>,
+    pub track_number: Option<u32>,
+    pub disc: Option<u32>,
+    pub year: Option<i32>,
+    pub genre: Option<String>,
+}
+
+impl Tags {
+    /// Fill in whatever is still missing from a metadata revision.
+    /// Tags that were already found take precedence.
+    fn update(&mut self, revision: &MetadataRevision) {
+        let mut album_artist = None;
+
+        for tag in revision.tags() {
+            // Some formats pad their strings with NULs
+            let value = tag.value.to_string();
+            let value = value
+                .trim_matches(|c: char| c.is_whitespace() || c == '\0')
+                .to_owned();
+            if value.is_empty() {
+                continue;
+            }
+
+            match tag.std_key {
+                Some(StandardTagKey::Artist) => {
+                    self.artist.get_or_insert(value);
+                }
+                Some(StandardTagKey::AlbumArtist) => {
+                    album_artist.get_or_insert(value);
+                }
+                Some(StandardTagKey::Album) => {
+                    self.album.get_or_insert(value);
+                }
+                Some(StandardTagKey::TrackTitle) => {
+                    self.title.get_or_insert(value);
+                }
+                Some(StandardTagKey::TrackNumber) => {
+                    self.track_number = self.track_number.or_else(|| parse_position(&value));
+                }
+                Some(StandardTagKey::DiscNumber) => {
+                    self.disc = self.disc.or_else(|| parse_position(&value));
+                }
+                Some(StandardTagKey::Date | StandardTagKey::OriginalDate) => {
+                    self.year = self.year.or_else(|| parse_year(&value));
+                }
+                Some(StandardTagKey::Genre) => {
+                    self.genre.get_or_insert(value);
+                }
+                _ => {}
+            }
+        }
+
+        if self.artist.is_none() {
+            self.artist = album_artist;
+        }
+    }
+}
+
+impl Tags {
+    /// The name to show the user for a track at `path` with these tags
+    pub fn display_name(&self, path: &Path) -> String {
+        match (&self.artist, &self.title) {
+            (Some(artist), Some(title)) => format!("{} - {}", artist, title),
+            (None, Some(title)) => title.clone(),
+            _ => path.display().to_string(),
+        }
+    }
+}
+
+/// Parse track and disc numbers, which are often written as "3/12"
+fn parse_position(value: &str) -> Option<u32> {
+    value.split('/').next()?.trim().parse().ok()
+}
+
+/// Parse the year out of dates such as "2003" or "2003-05-01"
+fn parse_year(value: &str) -> Option<i32> {
+    value.get(..4)?.parse().ok()
+}
+
+impl From<&Track> for TrackInfo {
+    fn from(track: &Track) -> Self {
+        Self {
+            path: track.path.clone(),
+            duration: track.duration,
+            tags: track.tags.clone(),
+        }
+    }
+}
+
+/// Identifies a version of a file, to tell whether it changed since it was indexed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileStamp {
+    pub size: u64,
+    pub modified: SystemTime,
+}
+
+impl FileStamp {
+    fn of(metadata: &fs::Metadata) -> Result<Self> {
+        Ok(Self {
+            size: metadata.len(),
+            modified: metadata.modified()?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Track {
+    /// Path relative to the library root
+    pub path: PathBuf,
+    pub format: Format,
+    pub duration: Option<Duration>,
+    pub tags: Tags,
+    pub stamp: FileStamp,
+}
+
+impl Track {
+    fn load(root: &Path, path: PathBuf, format: Format, stamp: FileStamp) -> Result<Self> {
+        let (duration, tags) = probe(&root.join(&path), &path)?;
+        Ok(Self {
+            path,
+            format,
+            duration,
+            tags,
+            stamp,
+        })
+    }
+
+    /// The name to show the user - "artist - title" when tagged, the path otherwise
+    pub fn display_name(&self) -> String {
+        self.tags.display_name(&self.path)
+    }
+
+    /// Sort key for listing tracks in album order
+    fn album_order(&self) -> impl Ord + '_ {
+        (
+            &self.tags.artist,
+            self.tags.year,
+            &self.tags.album,
+            self.tags.disc,
+            self.tags.track_number,
+            &self.path,
+        )
+    }
+}
+
+fn probe(full_path: &Path, path: &Path) -> Result<(Option<Duration>, Tags)> {
+    let stream = MediaSourceStream::new(Box::new(File::open(full_path)?), Default::default());
+
+    let mut hint = Hint::new();
+    if let Some(extension) = path.extension().and_then(|ext| ext.to_str()) {
+        hint.with_extension(extension);
+    }
+
+    let mut probed = symphonia::default::get_probe().format(
+        &hint,
+        stream,
+        &FormatOptions::default(),
+        &MetadataOptions::default(),
+    )?;
+
+    // Tags in front of the container (e.g. ID3v2) come first, then the container's own
+    let mut tags = Tags::default();
+    if let Some(revision) = probed
+        .metadata
+        .get()
+        .as_mut()
+        .and_then(|m| m.skip_to_latest())
+    {
+        tags.update(revision);
+    }
+    if let Some(revision) = probed.format.metadata().skip_to_latest() {
+        tags.update(revision);
+    }
+
+    let duration = probed.format.default_track().and_then(|track| {
+        let params = &track.codec_params;
+        let time = params.time_base?.calc_time(params.n_frames?);
+        Some(Duration::from_secs(time.seconds) + Duration::from_secs_f64(time.frac))
+    });
+
+    Ok((duration, tags))
+}
+
+/// The on-disk format of the library index
+#[derive(Debug, Serialize, Deserialize)]
+struct Cache {
+    root: PathBuf,
+    tracks: Vec<Track>,
+}
+
+/// What a query found in the library
+#[derive(Debug)]
+pub enum Found<'a> {
+    /// The tracks the query selects, possibly none
+    Tracks(Vec<&'a Track>),
+    /// The tracks matching a query meant to select only one of them
+    Ambiguous(Vec<&'a Track>),
+}
+
+/// The tracks found under the server's music library path
+#[derive(Debug, Default)]
+pub struct Library {
+    root: PathBuf,
+    cache_path: PathBuf,
+    tracks: BTreeMap<PathBuf, Track>,
+    /// Playlist files, relative to the root
+    playlists: BTreeSet<PathBuf>,
+}
+
+impl Library {
+    /// Load the library index from the cache and bring it up to date with `root`
+    pub fn open(root: PathBuf, cache_path: PathBuf) -> Result<Self> {
+        // Watchers report absolute paths, which have to start with the root
+        let mut library = Self {
+            root: fs::canonicalize(root)?,
+            cache_path,
+            tracks: BTreeMap::new(),
+            playlists: BTreeSet::new(),
+        };
+
+        match library.load_cache() {
+            Ok(()) => info!("Loaded {} cached tracks", library.tracks.len()),
+            Err(err) => warn!("Not using library cache {:?}: {}", library.cache_path, err),
+        }
+        library.rescan()?;
+
+        Ok(library)
+    }
+
+    fn load_cache(&mut self) -> Result<()> {
+        let cache: Cache = serde_json::from_reader(BufReader::new(File::open(&self.cache_path)?))?;
+        if cache.root != self.root {
+            return Err(DoodleError::Generic(format!(
+                "cache is for a different library ({:?})",
+                cache.root
+            )))
+            .as_eyre_result();
+        }
+
+        self.tracks = cache
+            .tracks
+            .into_iter()
+            .map(|track| (track.path.clone(), track))
+            .collect();
+        Ok(())
+    }
+
+    fn save_cache(&self) -> Result<()> {
+        let cache = Cache {
+            root: self.root.clone(),
+            tracks: self.tracks().cloned().collect(),
+        };
+
+        common::atomic_write(&self.cache_path, &serde_json::to_vec(&cache)?)
+    }
+
+    /// Recursively scan the library root for supported audio files.
+    ///
+    /// Only files that are new or changed since they were last indexed get
+    /// their tags read again, and files that no longer exist are dropped.
+    pub fn rescan(&mut self) -> Result<RescanResp> {
+        info!("Scanning music library at {:?}", self.root);
+
+        let mut previous = std::mem::take(&mut self.tracks);
+        let previous_playlists = std::mem::take(&mut self.playlists);
+        let mut summary = RescanResp::default();
+        if let Err(err) = self.scan_dir(PathBuf::new(), &mut previous, &mut summary) {
+            self.tracks = previous;
+            self.playlists = previous_playlists;
+            return Err(err);
+        }
+        summary.removed = previous.len();
+        summary.tracks = self.tracks.len();
+
+        info!(
+            "Found {} tracks ({} added, {} updated, {} removed)",
+            summary.tracks, summary.added, summary.updated, summary.removed
+        );
+
+        self.save_cache_logged();
+
+        Ok(summary)
+    }
+
+    /// Bring specific files or directories up to date, given as absolute paths
+    /// such as the ones reported by a `Watcher`
+    pub fn update<I: IntoIterator<Item = PathBuf>>(&mut self, paths: I) -> RescanResp {
+        let mut summary = RescanResp::default();
+
+        for full_path in paths {
+            let Ok(path) = full_path.strip_prefix(&self.root).map(Path::to_owned) else {
+                warn!("Ignoring change outside the library: {:?}", full_path);
+                continue;
+            };
+
+            // Forget everything under the path, then whatever still exists is found again
+            let (mut previous, kept) = std::mem::take(&mut self.tracks)
+                .into_iter()
+                .partition(|(track_path, _)| track_path.starts_with(&path));
+            self.tracks = kept;
+            self.playlists
+                .retain(|playlist| !playlist.starts_with(&path));
+
+            match fs::metadata(&full_path) {
+                Ok(metadata) if metadata.is_dir() => {
+                    if let Err(err) = self.scan_dir(path.clone(), &mut previous, &mut summary) {
+                        warn!("Failed scanning {:?}: {}", path, err);
+                    }
+                }
+                Ok(metadata) => {
+                    if PlaylistFormat::from_path(&path).is_some() {
+                        self.playlists.insert(path);
+                    } else if let Some(format) = Format::from_path(&path) {
+                        self.scan_file(path, format, &metadata, &mut previous, &mut summary)
+                    }
+                }
+                Err(_) => {}
+            }
+            summary.removed += previous.len();
+        }
+        summary.tracks = self.tracks.len();
+
+        if summary.changed() {
+            info!(
+                "Library changed ({} added, {} updated, {} removed)",
+                summary.added, summary.updated, summary.removed
+            );
+            self.save_cache_logged();
+        }
+
+        summary
+    }
+
+    fn save_cache_logged(&self) {
+        if let Err(err) = self.save_cache() {
+            warn!("Failed saving library cache {:?}: {}", self.cache_path, err);
+        }
+    }
+
+    fn scan_dir(
+        &mut self,
+        relative: PathBuf,
+        previous: &mut BTreeMap<PathBuf, Track>,
+        summary: &mut RescanResp,
+    ) -> Result<()> {
+        for entry in self.root.join(&relative).read_dir()? {
+            let entry = entry?;
+            let path = relative.join(entry.file_name());
+
+            // Follow symlinks to files, but not to directories to avoid loops
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                if let Err(err) = self.scan_dir(path.clone(), previous, summary) {
+                    warn!("Failed scanning {:?}: {}", path, err);
+                }
+                continue;
+            }
+
+            if PlaylistFormat::from_path(&path).is_some() {
+                debug!("Found playlist {:?}", path);
+                self.playlists.insert(path);
+                continue;
+            }
+            let Some(format) = Format::from_path(&path) else {
+                debug!("Ignoring {:?}", path);
+                continue;
+            };
+
+            match fs::metadata(self.root.join(&path)) {
+                Ok(metadata) if metadata.is_file() => {
+                    self.scan_file(path, format, &metadata, previous, summary)
+                }
+                Ok(_) => {}
+                Err(err) => warn!("Ignoring {:?}: {}", path, err),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn scan_file(
+        &mut self,
+        path: PathBuf,
+        format: Format,
+        metadata: &fs::Metadata,
+        previous: &mut BTreeMap<PathBuf, Track>,
+        summary: &mut RescanResp,
+    ) {
+        let stamp = match FileStamp::of(metadata) {
+            Ok(stamp) => stamp,
+            Err(err) => {
+                warn!("Ignoring {:?}: {}", path, err);
+                return;
+            }
+        };
+
+        let counter = match previous.remove(&path) {
+            Some(track) if track.stamp == stamp => {
+                self.tracks.insert(path, track);
+                return;
+            }
+            Some(_) => &mut summary.updated,
+            None => &mut summary.added,
+        };
+
+        match Track::load(&self.root, path.clone(), format, stamp) {
+            Ok(track) => {
+                debug!("Found {:?}", track);
+                self.tracks.insert(path, track);
+                *counter += 1;
+            }
+            Err(err) => warn!("Ignoring unreadable {:?}: {}", path, err),
+        }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// All tracks, ordered by path
+    pub fn tracks(&self) -> impl Iterator<Item = &Track> {
+        self.tracks.values()
+    }
+
+    /// The track at `path`, relative to the library root
+    pub fn track(&self, path: &Path) -> Option<&Track> {
+        self.tracks.get(path)
+    }
+
+    /// Find a playlist file by its path relative to the library root, or by a
+    /// case-insensitive match of the path or its file name, with or without extension
+    pub fn find_playlist(&self, name: &str) -> Option<&Path> {
+        if let Some(playlist) = self.playlists.get(Path::new(name)) {
+            return Some(playlist);
+        }
+
+        let name = UniCase::new(name);
+        let mut matches = self.playlists.iter().filter(|playlist| {
+            let path = playlist.to_string_lossy();
+            let without_extension = playlist.with_extension("");
+            let without_extension = without_extension.to_string_lossy();
+            let file_name = playlist.file_name().unwrap_or_default().to_string_lossy();
+            let stem = playlist.file_stem().unwrap_or_default().to_string_lossy();
+            [path, without_extension, file_name, stem]
+                .iter()
+                .any(|candidate| UniCase::new(candidate.as_ref()) == name)
+        });
+
+        match (matches.next(), matches.next()) {
+            (Some(playlist), None) => Some(playlist),
+            _ => None,
+        }
+    }
+
+    /// Find the tracks matching a user's query.
+    ///
+    /// A query of the form "artist:<name>", "album:<name>", "genre:<name>" or
+    /// "title:<name>" selects every track with that tag, in album order.
+    ///
+    /// Any other query selects a single track by its path relative to the library
+    /// root, or by a case-insensitive match of the path, file name, title or
+    /// "artist - title", giving every candidate when more than one track matches.
+    pub fn search(&self, query: &str) -> Found<'_> {
+        if let Some((field, value)) = query.split_once(':') {
+            let field: fn(&Tags) -> &Option<String> = match field {
+                "artist" => |tags| &tags.artist,
+                "album" => |tags| &tags.album,
+                "genre" => |tags| &tags.genre,
+                "title" => |tags| &tags.title,
+                _ => return self.find(query),
+            };
+
+            let value = UniCase::new(value.trim());
+            let mut tracks: Vec<_> = self
+                .tracks()
+                .filter(|track| {
+                    field(&track.tags)
+                        .as_deref()
+                        .is_some_and(|tag| UniCase::new(tag) == value)
+                })
+                .collect();
+            tracks.sort_by(|a, b| a.album_order().cmp(&b.album_order()));
+            return Found::Tracks(tracks);
+        }
+
+        self.find(query)
+    }
+
+    fn find(&self, query: &str) -> Found<'_> {
+        if let Some(track) = self.tracks.get(Path::new(query)) {
+            return Found::Tracks(vec![track]);
+        }
+
+        let query = UniCase::new(query);
+        let mut matches = self.tracks().filter(|track| {
+            let path = track.path.to_string_lossy();
+            let stem = track.path.file_stem().unwrap_or_default().to_string_lossy();
+            let file_name = track.path.file_name().unwrap_or_default().to_string_lossy();
+            let tagged = [track.tags.title.clone(), Some(track.display_name())];
+            [path, stem, file_name]
+                .into_iter()
+                .map(|candidate| candidate.into_owned())
+                .chain(tagged.into_iter().flatten())
+                .any(|candidate| UniCase::new(candidate.as_str()) == query)
+        });
+
+        match (matches.next(), matches.next()) {
+            (Some(a), Some(b)) => Found::Ambiguous([a, b].into_iter().chain(matches).collect()),
+            (track, _) => Found::Tracks(track.into_iter().collect()),
+        }
+    }
+}
+
+/// Watches the library root for changes, reporting them once things settle down
+pub struct Watcher {
+    _watcher: notify::RecommendedWatcher,
+    receiver: mpsc::Receiver<notify::Result<notify::Event>>,
+    changed: BTreeSet<PathBuf>,
+    last_change: Option<Instant>,
+}
+
+impl Watcher {
+    pub fn new(root: &Path) -> Result<Self> {
+        let (tx, rx) = mpsc::channel();
+        let mut watcher = notify::recommended_watcher(tx)?;
+        watcher.watch(root, RecursiveMode::Recursive)?;
+        info!("Watching {:?} for changes", root);
+
+        Ok(Self {
+            _watcher: watcher,
+            receiver: rx,
+            changed: BTreeSet::new(),
+            last_change: None,
+        })
+    }
+
+    /// Collect the pending change notifications without blocking.
+    /// Returns the changed paths once nothing has changed for a while.
+    pub fn poll(&mut self) -> Option<BTreeSet<PathBuf>> {
+        while let Ok(event) = self.receiver.try_recv() {
+            match event {
+                Ok(event) if matches!(event.kind, EventKind::Access(_)) => {}
+                Ok(event) => {
+                    debug!("Library {:?}: {:?}", event.kind, event.paths);
+                    self.changed.extend(event.paths);
+                    self.last_change = Some(Instant::now());
+                }
+                Err(err) => warn!("Library watch error: {}", err),
+            }
+        }
+
+        match self.last_change {
+            Some(last_change) if last_change.elapsed() >= WATCH_SETTLE => {
+                self.last_change = None;
+                Some(std::mem::take(&mut self.changed))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use symphonia::core::meta::{MetadataBuilder, Tag, Value};
+
+    use super::*;
+
+    fn track(path: &str, tags: Tags) -> Track {
+        Track {
+            path: PathBuf::from(path),
+            format: Format::Mp3,
+            duration: None,
+            tags,
+            stamp: FileStamp {
+                size: 0,
+                modified: SystemTime::UNIX_EPOCH,
+            },
+        }
+    }
+
+    fn tagged(artist: &str, album: &str, track_number: u32, title: &str) -> Tags {
+        Tags {
+            artist: Some(artist.to_owned()),
+            album: Some(album.to_owned()),
+            title: Some(title.to_owned()),
+            track_number: Some(track_number),
+            genre: Some("Rock".to_owned()),
+            ..Default::default()
+        }
+    }
+
+    fn library() -> Library {
+        let tracks = [
+            track("b/02.mp3", tagged("The Band", "Second", 2, "Outro")),
+            track("b/01.mp3", tagged("The Band", "Second", 1, "Intro")),
+            track("a/01.mp3", tagged("The Band", "First", 1, "Intro")),
+            track("c/song.mp3", tagged("Other Band", "Other", 1, "Song")),
+            track("untagged/Song.ogg", Tags::default()),
+        ];
+        Library {
+            tracks: tracks
+                .into_iter()
+                .map(|track| (track.path.clone(), track))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    /// What a search found, as the paths of the tracks
+    fn search(library: &Library, query: &str) -> Result<Vec<String>, Vec<String>> {
+        let paths = |tracks: Vec<&Track>| {
+            tracks
+                .iter()
+                .map(|track| track.path.to_string_lossy().into_owned())
+                .collect()
+        };
+        match library.search(query) {
+            Found::Tracks(tracks) => Ok(paths(tracks)),
+            Found::Ambiguous(candidates) => Err(paths(candidates)),
+        }
+    }
+
+    #[test]
+    fn searching_by_field() {
+        let library = library();
+        let all_by_the_band = Ok(vec![
+            "a/01.mp3".to_owned(),
+            "b/01.mp3".to_owned(),
+            "b/02.mp3".to_owned(),
+        ]);
+        assert_eq!(search(&library, "artist:The Band"), all_by_the_band);
+        assert_eq!(search(&library, "artist: the band "), all_by_the_band);
+        assert_eq!(
+            search(&library, "album:second"),
+            Ok(vec!["b/01.mp3".to_owned(), "b/02.mp3".to_owned()])
+        );
+        assert_eq!(
+            search(&library, "genre:rock").map(|found| found.len()),
+            Ok(4)
+        );
+        assert_eq!(
+            search(&library, "title:Intro"),
+            Ok(vec!["a/01.mp3".to_owned(), "b/01.mp3".to_owned()])
+        );
+        assert_eq!(search(&library, "artist:Nobody"), Ok(vec![]));
+        // Not a field, so not a field search
+        assert_eq!(search(&library, "mood:happy"), Ok(vec![]));
+    }
+
+    #[test]
+    fn searching_for_one_track() {
+        let library = library();
+        assert_eq!(
+            search(&library, "b/02.mp3"),
+            Ok(vec!["b/02.mp3".to_owned()])
+        );
+        assert_eq!(search(&library, "OUTRO"), Ok(vec!["b/02.mp3".to_owned()]));
+        assert_eq!(
+            search(&library, "other band - song"),
+            Ok(vec!["c/song.mp3".to_owned()])
+        );
+        assert_eq!(
+            search(&library, "Song.ogg"),
+            Ok(vec!["untagged/Song.ogg".to_owned()])
+        );
+        assert_eq!(search(&library, "Missing"), Ok(vec![]));
+    }
+
+    #[test]
+    fn ambiguous_searches_give_the_candidates() {
+        let library = library();
+        assert_eq!(
+            search(&library, "intro"),
+            Err(vec!["a/01.mp3".to_owned(), "b/01.mp3".to_owned()])
+        );
+        // A title and a file name
+        assert_eq!(
+            search(&library, "song"),
+            Err(vec![
+                "c/song.mp3".to_owned(),
+                "untagged/Song.ogg".to_owned()
+            ])
+        );
+    }
+
+    #[test]
+    fn positions() {
+        assert_eq!(parse_position("3"), Some(3));
+        assert_eq!(parse_position("3/12"), Some(3));
+        assert_eq!(parse_position(" 07 / 12 "), Some(7));
+        assert_eq!(parse_position("/12"), None);
+        assert_eq!(parse_position("A1"), None);
+        assert_eq!(parse_position(""), None);
+    }
+
+    #[test]
+    fn years() {
+        assert_eq!(parse_year("2003"), Some(2003));
+        assert_eq!(parse_year("2003-05-01"), Some(2003));
+        assert_eq!(parse_year("1999-12-31T23:59:59"), Some(1999));
+        assert_eq!(parse_year("03"), None);
+        assert_eq!(parse_year("May 2003"), None);
+        assert_eq!(parse_year(""), None);
+    }
+
+    #[test]
+    fn updating_tags() {
+        let revision = |tags: &[(StandardTagKey, &str)]| {
+            let mut builder = MetadataBuilder::new();
+            for (key, value) in tags {
+                builder.add_tag(Tag::new(Some(*key), "", Value::from(*value)));
+            }
+            builder.metadata()
+        };
+
+        let mut tags = Tags::default();
+        tags.update(&revision(&[
+            (StandardTagKey::AlbumArtist, "Album Artist"),
+            (StandardTagKey::TrackTitle, "Title\0\0"),
+            (StandardTagKey::TrackNumber, "3/12"),
+            (StandardTagKey::DiscNumber, "1/2"),
+            (StandardTagKey::Date, "2003-05-01"),
+            (StandardTagKey::Genre, "  "),
+        ]));
+        // Tags that were already found are kept
+        tags.update(&revision(&[
+            (StandardTagKey::TrackTitle, "Other Title"),
+            (StandardTagKey::TrackNumber, "4"),
+            (StandardTagKey::Album, "Album"),
+            (StandardTagKey::Genre, "Pop"),
+        ]));
+
+        assert_eq!(
+            tags,
+            Tags {
+                artist: Some("Album Artist".to_owned()),
+                album: Some("Album".to_owned()),
+                title: Some("Title".to_owned()),
+                track_number: Some(3),
+                disc: Some(1),
+                year: Some(2003),
+                genre: Some("Pop".to_owned()),
+            }
+        );
+
+        // The album artist only stands in for a missing artist
+        let mut tags = Tags::default();
+        tags.update(&revision(&[
+            (StandardTagKey::AlbumArtist, "Album Artist"),
+            (StandardTagKey::Artist, "Artist"),
+        ]));
+        assert_eq!(tags.artist.as_deref(), Some("Artist"));
+    }
+}
@@ -2,7 +2,15 @@ pub mod common;
 pub mod error;
 pub(crate) mod client;
 pub(crate) mod cmdline;
+pub(crate) mod library;
+pub(crate) mod output;
+pub(crate) mod playlist;
+pub(crate) mod queue;
 pub(crate) mod server;
+pub(crate) mod settings;
+pub(crate) mod shell;
+pub(crate) mod sources;
+pub(crate) mod tui;
 
 use common::Address;
 use log::{info, debug};
@@ -52,14 +60,14 @@ pub fn get_version() -> &'static str {
         static ref FULL_VERSION: String = format!("magical-doodle {}{}", VERSION, EXTRA);
     }
 
-    return FULL_VERSION.as_str();
+    FULL_VERSION.as_str()
 }
 
 pub fn os_string() -> String {
     match (sys_info::os_release().ok(), sys_info::os_type().ok()) {
         (Some(release), Some(os_type)) =>
             format!("{}, kernel-ver {}", os_type, release),
-        _ => format!("Unknown"),
+        _ => "Unknown".to_owned(),
     }
 }
 
@@ -79,14 +87,17 @@ fn logger_init(opt: &cmdline::Opt) -> color_eyre::eyre::Result<()> {
         .set_thread_padding(simplelog::ThreadPadding::Right(6))
         .set_time_format_custom(
             format_description!("[year]-[month]-[day] [hour]:[minute]:[second].[subsecond digits:6]"))  // "%Y-%m-%d %H:%M:%S.%6f"
-        .set_time_offset_to_local().map_or_else(|b| { eprintln!("Failed to set time offset"); b }, |b| b)
+        .set_time_offset_to_local().unwrap_or_else(|b| { eprintln!("Failed to set time offset"); b })
         .build();
 
     let log_level = opt.log_level.into();
 
-    let mut loggers: Vec<Box<(dyn SharedLogger + 'static)>> = Vec::with_capacity(2);
+    let mut loggers: Vec<Box<dyn SharedLogger + 'static>> = Vec::with_capacity(2);
 
-    if !opt.quiet {
+    // The TUI takes over the terminal, and log lines would be written over it
+    let tui = matches!(&opt.command, cmdline::Command::Client(cmdline::Client { command: cmdline::ClientCommand::Tui }));
+
+    if !opt.quiet && !tui {
         loggers.push(
             TermLogger::new(log_level, config.clone(), TerminalMode::Stderr, ColorChoice::Auto)
         )
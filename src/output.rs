@@ -0,0 +1,222 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use color_eyre::eyre::Result;
+use log::{error, info};
+use rodio::cpal::traits::{DeviceTrait, HostTrait};
+use rodio::dynamic_mixer::{self, DynamicMixer, DynamicMixerController};
+use rodio::{OutputStream, OutputStreamHandle, Sink};
+
+use crate::cmdline::OutputKind;
+use crate::error::{AsEyreErrorResult, DoodleError};
+
+/// Channel count used when rendering without a device
+const RENDER_CHANNELS: u16 = 2;
+/// Sample rate used when rendering without a device
+const RENDER_SAMPLE_RATE: u32 = 44100;
+/// How much audio the render thread produces at a time
+const RENDER_CHUNK: Duration = Duration::from_millis(20);
+
+type WavWriter = hound::WavWriter<BufWriter<File>>;
+
+/// Something the player can play sinks through
+pub trait AudioOutput {
+    /// Create a new sink that plays through this output
+    fn new_sink(&self) -> Result<Sink>;
+}
+
+/// Open the output described by `kind`.
+///
+/// Note that the device outputs are not `Send`, so this should be called
+/// on the thread that is going to use the output.
+pub fn open(kind: &OutputKind) -> Result<Box<dyn AudioOutput>> {
+    info!("Opening {} audio output", kind);
+    Ok(match kind {
+        OutputKind::Default => Box::new(StreamOutput::from(OutputStream::try_default()?)),
+        OutputKind::Device(name) => Box::new(StreamOutput::from(OutputStream::try_from_device(
+            &find_device(name)?,
+        )?)),
+        OutputKind::Null => Box::new(RenderOutput::new(None)?),
+        OutputKind::Wav(path) => Box::new(RenderOutput::new(Some(create_wav(path)?))?),
+    })
+}
+
+fn find_device(name: &str) -> Result<rodio::Device> {
+    let mut available = vec![];
+    for device in rodio::cpal::default_host().output_devices()? {
+        match device.name() {
+            Ok(device_name) if device_name == name => return Ok(device),
+            Ok(device_name) => available.push(device_name),
+            Err(_) => {}
+        }
+    }
+
+    Err(DoodleError::Generic(format!(
+        "no output device named {:?}, available devices: {:?}",
+        name, available
+    )))
+    .as_eyre_result()
+}
+
+fn create_wav(path: &Path) -> Result<WavWriter> {
+    let spec = hound::WavSpec {
+        channels: RENDER_CHANNELS,
+        sample_rate: RENDER_SAMPLE_RATE,
+        bits_per_sample: 32,
+        sample_format: hound::SampleFormat::Float,
+    };
+
+    Ok(hound::WavWriter::create(path, spec)?)
+}
+
+/// Plays on a real device through cpal
+struct StreamOutput {
+    _stream: OutputStream,
+    handle: OutputStreamHandle,
+}
+
+impl From<(OutputStream, OutputStreamHandle)> for StreamOutput {
+    fn from((stream, handle): (OutputStream, OutputStreamHandle)) -> Self {
+        Self {
+            _stream: stream,
+            handle,
+        }
+    }
+}
+
+impl AudioOutput for StreamOutput {
+    fn new_sink(&self) -> Result<Sink> {
+        Ok(Sink::try_new(&self.handle)?)
+    }
+}
+
+/// Mixes the sinks on a thread of its own at real-time pace,
+/// optionally writing the result into a WAV file.
+///
+/// Only the time where a sink is attached gets written, so an idle server
+/// doesn't fill the WAV file with silence. A paused sink stays attached though,
+/// and the silence while it's paused is written.
+struct RenderOutput {
+    mixer: Arc<DynamicMixerController<f32>>,
+    running: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl RenderOutput {
+    fn new(writer: Option<WavWriter>) -> Result<Self> {
+        let (mixer, output) = dynamic_mixer::mixer(RENDER_CHANNELS, RENDER_SAMPLE_RATE);
+        let running = Arc::new(AtomicBool::new(true));
+
+        let thread_running = running.clone();
+        let thread = thread::Builder::new()
+            .name("render".to_owned())
+            .spawn(move || render(output, writer, &thread_running))
+            .as_eyre_result()?;
+
+        Ok(Self {
+            mixer,
+            running,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl AudioOutput for RenderOutput {
+    fn new_sink(&self) -> Result<Sink> {
+        let (sink, queue) = Sink::new_idle();
+        self.mixer.add(queue);
+        Ok(sink)
+    }
+}
+
+impl Drop for RenderOutput {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+        if let Some(th) = self.thread.take() {
+            let _ = th.join();
+        }
+    }
+}
+
+fn render(mut output: DynamicMixer<f32>, mut writer: Option<WavWriter>, running: &AtomicBool) {
+    let chunk_samples =
+        (RENDER_SAMPLE_RATE as u128 * RENDER_CHANNELS as u128 * RENDER_CHUNK.as_micros()
+            / 1_000_000) as usize;
+    let mut buffer = Vec::with_capacity(chunk_samples);
+
+    let start = Instant::now();
+    let mut rendered = Duration::ZERO;
+
+    while running.load(Ordering::SeqCst) {
+        buffer.clear();
+        // The mixer runs dry (returns `None`) whenever no sink is attached to it
+        buffer.extend(output.by_ref().take(chunk_samples));
+
+        if let Some(wav) = &mut writer {
+            if let Err(err) = buffer
+                .iter()
+                .try_for_each(|sample| wav.write_sample(*sample))
+            {
+                error!("Failed writing to WAV output, no longer recording: {}", err);
+                writer = None;
+            }
+        }
+
+        rendered += RENDER_CHUNK;
+        if let Some(wait) = (start + rendered).checked_duration_since(Instant::now()) {
+            thread::sleep(wait);
+        }
+    }
+
+    if let Some(wav) = writer {
+        if let Err(err) = wav.finalize() {
+            error!("Failed finalizing WAV output: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use rodio::buffer::SamplesBuffer;
+
+    use super::*;
+
+    #[test]
+    fn renders_played_samples_into_wav() {
+        let path = std::env::temp_dir().join(format!("doodle-render-{}.wav", std::process::id()));
+        let frames = RENDER_SAMPLE_RATE as usize / 10;
+
+        let output = RenderOutput::new(Some(create_wav(&path).unwrap())).unwrap();
+        let sink = output.new_sink().unwrap();
+        // Mono, like the silence an idle sink plays, which the mixer spreads on both channels
+        sink.append(SamplesBuffer::new(
+            1,
+            RENDER_SAMPLE_RATE,
+            vec![0.5f32; frames],
+        ));
+        sink.sleep_until_end();
+        drop(sink);
+        drop(output);
+
+        let mut reader = hound::WavReader::open(&path).unwrap();
+        let spec = reader.spec();
+        let samples: Vec<f32> = reader.samples().map(Result::unwrap).collect();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(spec.channels, RENDER_CHANNELS);
+        assert_eq!(spec.sample_rate, RENDER_SAMPLE_RATE);
+        assert_eq!(spec.sample_format, hound::SampleFormat::Float);
+        // The sink's queue is attached from its creation until it's dropped,
+        // so some silence may surround what was played
+        let played: Vec<_> = samples.iter().filter(|sample| **sample != 0.0).collect();
+        assert_eq!(played.len(), frames * RENDER_CHANNELS as usize);
+        assert!(played.iter().all(|sample| (*sample - 0.5).abs() < 1e-6));
+        assert_eq!(samples.len() % RENDER_CHANNELS as usize, 0);
+    }
+}
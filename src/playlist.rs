@@ -0,0 +1,614 @@
+use std::fs;
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+
+use color_eyre::eyre::Result;
+use unicase::UniCase;
+use url::Url;
+
+use crate::common;
+use crate::error::DoodleError;
+
+/// Where the playlists made through the server are kept by default, in the user's data directory.
+/// It's kept out of the library so its writes aren't picked up as library changes.
+pub fn default_store_path() -> Option<PathBuf> {
+    Some(
+        dirs::data_dir()?
+            .join(common::APP_DIR_NAME)
+            .join("Playlists"),
+    )
+}
+
+/// The extension of the playlists made through the server
+const STORE_EXTENSION: &str = "m3u8";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlaylistFormat {
+    /// Plain or extended M3U, including the UTF-8 M3U8
+    M3u,
+    Pls,
+    Xspf,
+}
+
+impl PlaylistFormat {
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let extension = UniCase::new(path.extension()?.to_str()?);
+        [
+            ("m3u", Self::M3u),
+            ("m3u8", Self::M3u),
+            ("pls", Self::Pls),
+            ("xspf", Self::Xspf),
+        ]
+        .into_iter()
+        .find_map(|(ext, format)| (UniCase::new(ext) == extension).then_some(format))
+    }
+}
+
+/// A track listed in a playlist file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// Where the playlist says the track is, as written
+    pub location: String,
+    pub title: Option<String>,
+    pub duration: Option<Duration>,
+    /// The line the entry is on, to point at broken entries
+    pub line: usize,
+    /// Whether the location is a URI, as in XSPF, rather than a path
+    pub uri: bool,
+}
+
+impl Entry {
+    /// An entry for the file at `path`, which should be absolute
+    /// so that it can be written relative to any playlist
+    pub fn new(path: &Path, title: Option<String>, duration: Option<Duration>) -> Self {
+        Self {
+            location: path.to_string_lossy().into_owned(),
+            title,
+            duration,
+            line: 0,
+            uri: false,
+        }
+    }
+
+    /// Where the entry points on disk, given the playlist's own path,
+    /// or why it doesn't point anywhere on disk.
+    /// Relative locations are relative to the playlist's directory.
+    pub fn resolve(&self, playlist: &Path) -> Result<PathBuf, String> {
+        let directory = playlist.parent().unwrap_or(Path::new(""));
+        let path = if self.uri || self.location.contains("://") {
+            // A URL, or in XSPF a URI reference that may be relative and percent-encoded
+            let url = Url::parse(&self.location)
+                .or_else(|_| {
+                    Url::from_directory_path(directory)
+                        .map_err(|_| url::ParseError::RelativeUrlWithoutBase)
+                        .and_then(|base| base.join(&self.location))
+                })
+                .map_err(|err| format!("invalid URL: {}", err))?;
+            if url.scheme() != "file" {
+                return Err(format!(
+                    "only local files can be played, not {} URLs",
+                    url.scheme()
+                ));
+            }
+            url.to_file_path()
+                .map_err(|_| "not a local file".to_owned())?
+        } else {
+            // Playlists written on Windows separate directories with backslashes
+            directory.join(self.location.replace('\\', "/"))
+        };
+
+        Ok(normalize(&path))
+    }
+}
+
+/// Drop the `.` and `..` components of a path without touching the file system,
+/// so entries can be matched with the library even when the files are missing
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+/// The shortest way to `path` from the directory `base`, both absolute,
+/// `None` when they have nothing but the file system root in common
+fn relative_to(path: &Path, base: &Path) -> Option<PathBuf> {
+    let common = path
+        .components()
+        .zip(base.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+    if !path.is_absolute() || !base.is_absolute() || common < 2 {
+        return None;
+    }
+
+    let mut relative = PathBuf::new();
+    for _ in base.components().skip(common) {
+        relative.push("..");
+    }
+    relative.extend(path.components().skip(common));
+    Some(relative)
+}
+
+/// Read the entries of the playlist file at `path`
+pub fn read(path: &Path) -> Result<Vec<Entry>> {
+    let format = PlaylistFormat::from_path(path)
+        .ok_or_else(|| DoodleError::Generic(format!("not a playlist file: {:?}", path)))?;
+    let bytes = fs::read(path)?;
+    let text = decode(&bytes);
+    let text = text.trim_start_matches('\u{feff}');
+
+    Ok(match format {
+        PlaylistFormat::M3u => parse_m3u(text),
+        PlaylistFormat::Pls => parse_pls(text),
+        PlaylistFormat::Xspf => parse_xspf(text),
+    })
+}
+
+/// Playlists are meant to be UTF-8, but plain M3U files are often Latin-1
+fn decode(bytes: &[u8]) -> String {
+    match std::str::from_utf8(bytes) {
+        Ok(text) => text.to_owned(),
+        Err(_) => bytes.iter().map(|&byte| byte as char).collect(),
+    }
+}
+
+/// One location per line, each optionally preceded by an `#EXTINF:<seconds>,<title>` line
+fn parse_m3u(text: &str) -> Vec<Entry> {
+    let mut entries = vec![];
+    let mut title = None;
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if let Some(info) = line.strip_prefix("#EXTINF:") {
+            let (seconds, name) = info.split_once(',').unwrap_or((info, ""));
+            // Unknown durations are written as -1
+            let duration = seconds
+                .trim()
+                .parse()
+                .ok()
+                .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok());
+            let name = Some(name.trim().to_owned()).filter(|name| !name.is_empty());
+            title = Some((name, duration));
+        } else if !line.is_empty() && !line.starts_with('#') {
+            let (title, duration) = title.take().unwrap_or_default();
+            entries.push(Entry {
+                location: line.to_owned(),
+                title,
+                duration,
+                line: index + 1,
+                uri: false,
+            });
+        }
+    }
+    entries
+}
+
+/// An INI file with `File<n>` and `Title<n>` keys in its `[playlist]` section
+fn parse_pls(text: &str) -> Vec<Entry> {
+    let mut entries: Vec<(u32, Entry)> = vec![];
+    let mut titles = vec![];
+    for (index, line) in text.lines().enumerate() {
+        let Some((key, value)) = line.trim().split_once('=') else {
+            continue;
+        };
+        let (key, value) = (key.trim(), value.trim());
+        let number = |prefix: &str| -> Option<u32> {
+            key.get(..prefix.len())
+                .filter(|start| start.eq_ignore_ascii_case(prefix))?;
+            key[prefix.len()..].parse().ok()
+        };
+
+        if let Some(number) = number("File") {
+            entries.push((
+                number,
+                Entry {
+                    location: value.to_owned(),
+                    title: None,
+                    duration: None,
+                    line: index + 1,
+                    uri: false,
+                },
+            ));
+        } else if let Some(number) = number("Title") {
+            titles.push((number, value.to_owned()));
+        }
+    }
+
+    for (number, title) in titles {
+        if let Some((_, entry)) = entries.iter_mut().find(|(n, _)| *n == number) {
+            entry.title = Some(title);
+        }
+    }
+    entries.sort_by_key(|(number, _)| *number);
+    entries.into_iter().map(|(_, entry)| entry).collect()
+}
+
+/// The `<location>` and `<title>` of each `<track>` in the XML.
+///
+/// This only looks for those elements rather than fully parsing the XML,
+/// which is all XSPF playlists need in practice.
+fn parse_xspf(text: &str) -> Vec<Entry> {
+    let line_at = |offset: usize| text[..offset].matches('\n').count() + 1;
+
+    let mut entries = vec![];
+    let mut rest = 0;
+    while let Some((track, start, end)) = element(text, rest, "track") {
+        rest = end;
+        let Some((location, _, _)) = element(track, 0, "location") else {
+            continue;
+        };
+        entries.push(Entry {
+            location: unescape(location.trim()),
+            title: element(track, 0, "title").map(|(title, _, _)| unescape(title.trim())),
+            duration: None,
+            line: line_at(start),
+            uri: true,
+        });
+    }
+    entries
+}
+
+/// Find the next `<name>...</name>` or `<name/>` element at or after `from`,
+/// returns its content, where it starts and where it ends
+fn element<'a>(text: &'a str, from: usize, name: &str) -> Option<(&'a str, usize, usize)> {
+    let open = format!("<{}", name);
+    let close = format!("</{}>", name);
+
+    let mut search = from;
+    let start = loop {
+        let start = search + text[search..].find(&open)?;
+        // Don't mistake e.g. <tracklist> for <track>
+        match text[start + open.len()..].chars().next() {
+            Some('>' | '/' | ' ' | '\t' | '\r' | '\n') => break start,
+            _ => search = start + open.len(),
+        }
+    };
+    let content_start = start + text[start..].find('>')? + 1;
+    if text[..content_start].ends_with("/>") {
+        return Some(("", start, content_start));
+    }
+    let content_end = content_start + text[content_start..].find(&close)?;
+    Some((
+        &text[content_start..content_end],
+        start,
+        content_end + close.len(),
+    ))
+}
+
+/// Replace XML character and entity references with the characters they stand for
+fn unescape(text: &str) -> String {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(amp) = rest.find('&') {
+        unescaped.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+        let Some(semicolon) = rest.find(';') else {
+            break;
+        };
+        let reference = &rest[1..semicolon];
+        let character = match reference {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => reference
+                .strip_prefix("#x")
+                .map(|hex| u32::from_str_radix(hex, 16))
+                .or_else(|| reference.strip_prefix('#').map(str::parse))
+                .and_then(Result::ok)
+                .and_then(char::from_u32),
+        };
+        match character {
+            Some(character) => {
+                unescaped.push(character);
+                rest = &rest[semicolon + 1..];
+            }
+            None => {
+                unescaped.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    unescaped.push_str(rest);
+    unescaped
+}
+
+/// Write `entries` to `path` as an extended M3U playlist in UTF-8.
+/// Absolute locations are written relative to the playlist's directory when they can be.
+pub fn write(path: &Path, entries: &[Entry]) -> Result<()> {
+    let directory = fs::canonicalize(path.parent().unwrap_or(Path::new(".")))?;
+
+    let mut contents = vec![];
+    writeln!(contents, "#EXTM3U")?;
+    for entry in entries {
+        let location = Path::new(&entry.location);
+        let location = match relative_to(location, &directory) {
+            Some(relative) => relative.to_string_lossy().into_owned(),
+            None => entry.location.clone(),
+        };
+        if entry.title.is_some() || entry.duration.is_some() {
+            let seconds = entry
+                .duration
+                .map_or(-1, |duration| duration.as_secs_f64().round() as i64);
+            let title = entry.title.as_deref().unwrap_or_default();
+            writeln!(contents, "#EXTINF:{},{}", seconds, title)?;
+        }
+        writeln!(contents, "{}", location)?;
+    }
+
+    common::atomic_write(path, &contents)
+}
+
+/// The playlists made through the server, kept as M3U8 files in one directory
+#[derive(Debug, Clone)]
+pub struct Store {
+    directory: PathBuf,
+}
+
+impl Store {
+    pub fn new(directory: PathBuf) -> Self {
+        Self { directory }
+    }
+
+    /// The file the playlist `name` is kept in,
+    /// `None` if the name can't be used as a file name
+    pub fn path(&self, name: &str) -> Option<PathBuf> {
+        let valid = !name.trim().is_empty()
+            && !name.starts_with('.')
+            && !name.contains(|c: char| c == '/' || c == '\\' || c.is_control());
+        valid.then(|| self.directory.join(format!("{}.{}", name, STORE_EXTENSION)))
+    }
+
+    /// The name a playlist is stored under, matching `name` regardless of case
+    /// unless there's an exact match
+    pub fn stored_name(&self, name: &str) -> Option<String> {
+        if self.path(name)?.is_file() {
+            return Some(name.to_owned());
+        }
+
+        let names = self.names().ok()?;
+        let mut matches = names
+            .into_iter()
+            .filter(|stored| UniCase::new(stored) == UniCase::new(name));
+        match (matches.next(), matches.next()) {
+            (Some(stored), None) => Some(stored),
+            _ => None,
+        }
+    }
+
+    /// The absolute path of the playlist `name`, if there is one
+    pub fn find(&self, name: &str) -> Option<PathBuf> {
+        let path = self.path(&self.stored_name(name)?)?;
+        fs::canonicalize(path).ok()
+    }
+
+    /// The names of all the playlists, sorted
+    pub fn names(&self) -> Result<Vec<String>> {
+        if !self.directory.exists() {
+            return Ok(vec![]);
+        }
+
+        let mut names = vec![];
+        for entry in self.directory.read_dir()? {
+            let path = entry?.path();
+            if !path.is_file() || path.extension() != Some(STORE_EXTENSION.as_ref()) {
+                continue;
+            }
+            if let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) {
+                names.push(name.to_owned());
+            }
+        }
+        names.sort_by(|a, b| UniCase::new(a).cmp(&UniCase::new(b)));
+        Ok(names)
+    }
+
+    pub fn save(&self, name: &str, entries: &[Entry]) -> Result<()> {
+        let path = self
+            .path(name)
+            .ok_or_else(|| DoodleError::Generic(format!("invalid playlist name: {:?}", name)))?;
+        fs::create_dir_all(&self.directory)?;
+        write(&path, entries)
+    }
+
+    pub fn delete(&self, name: &str) -> Result<()> {
+        let path = self
+            .path(name)
+            .ok_or_else(|| DoodleError::Generic(format!("invalid playlist name: {:?}", name)))?;
+        fs::remove_file(path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    fn locations(entries: &[Entry]) -> Vec<&str> {
+        entries
+            .iter()
+            .map(|entry| entry.location.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn m3u_titles_and_durations() {
+        let entries = parse_m3u(
+            "#EXTM3U\r\n\
+             #EXTINF:123,Artist - Title, with a comma\r\n\
+             Music/a.mp3\r\n\
+             \r\n\
+             # a comment\r\n\
+             #EXTINF:-1,Live stream\r\n\
+             b.ogg\r\n\
+             #EXTINF:42,\r\n\
+             c.flac\r\n\
+             d.wav\r\n",
+        );
+
+        assert_eq!(
+            locations(&entries),
+            ["Music/a.mp3", "b.ogg", "c.flac", "d.wav"]
+        );
+        let titles: Vec<_> = entries.iter().map(|entry| entry.title.as_deref()).collect();
+        assert_eq!(
+            titles,
+            [
+                Some("Artist - Title, with a comma"),
+                Some("Live stream"),
+                None,
+                None
+            ]
+        );
+        let durations: Vec<_> = entries.iter().map(|entry| entry.duration).collect();
+        assert_eq!(
+            durations,
+            [
+                Some(Duration::from_secs(123)),
+                None,
+                Some(Duration::from_secs(42)),
+                None
+            ]
+        );
+        let lines: Vec<_> = entries.iter().map(|entry| entry.line).collect();
+        assert_eq!(lines, [3, 7, 9, 10]);
+        assert!(entries.iter().all(|entry| !entry.uri));
+    }
+
+    #[test]
+    fn pls_entries_out_of_order() {
+        let entries = parse_pls(
+            "[playlist]\n\
+             NumberOfEntries=3\n\
+             Title2=Second\n\
+             File3=c.mp3\n\
+             file1 = a.mp3\n\
+             File2=b.mp3\n\
+             TITLE3=Third\n\
+             Length3=-1\n\
+             Version=2\n",
+        );
+
+        assert_eq!(locations(&entries), ["a.mp3", "b.mp3", "c.mp3"]);
+        let titles: Vec<_> = entries.iter().map(|entry| entry.title.as_deref()).collect();
+        assert_eq!(titles, [None, Some("Second"), Some("Third")]);
+        let lines: Vec<_> = entries.iter().map(|entry| entry.line).collect();
+        assert_eq!(lines, [5, 6, 4]);
+    }
+
+    #[test]
+    fn xspf_tracks() {
+        let entries = parse_xspf(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<playlist version="1" xmlns="http://xspf.org/ns/0/">
+  <title>Not a track</title>
+  <trackList>
+    <track>
+      <location>file:///music/Tom%20&amp;%20Jerry.mp3</location>
+      <title>Tom &amp; Jerry &lt;3 &#233;&#x e9;</title>
+    </track>
+    <track/>
+    <track >
+      <title>No location</title>
+    </track>
+    <track>
+      <location>
+        ../Other/b.ogg
+      </location>
+    </track>
+  </trackList>
+</playlist>
+"#,
+        );
+
+        assert_eq!(
+            locations(&entries),
+            ["file:///music/Tom%20&%20Jerry.mp3", "../Other/b.ogg"]
+        );
+        assert_eq!(entries[0].title.as_deref(), Some("Tom & Jerry <3 é&#x e9;"));
+        assert_eq!(entries[1].title, None);
+        let lines: Vec<_> = entries.iter().map(|entry| entry.line).collect();
+        assert_eq!(lines, [5, 13]);
+        assert!(entries.iter().all(|entry| entry.uri));
+    }
+
+    #[test]
+    fn self_closing_elements_are_empty() {
+        let text = "<track/><track><location>a.mp3</location></track>";
+        assert_eq!(element(text, 0, "track"), Some(("", 0, 8)));
+        assert_eq!(
+            element(text, 8, "track"),
+            Some(("<location>a.mp3</location>", 8, text.len()))
+        );
+
+        let text = "<track><location /><title>T</title></track>";
+        let (track, _, _) = element(text, 0, "track").unwrap();
+        assert_eq!(
+            element(track, 0, "location").map(|(content, _, _)| content),
+            Some("")
+        );
+        assert_eq!(
+            element(track, 0, "title").map(|(content, _, _)| content),
+            Some("T")
+        );
+
+        assert_eq!(
+            locations(&parse_xspf("<trackList><track/><track/></trackList>")),
+            Vec::<&str>::new()
+        );
+    }
+
+    #[test]
+    fn unescaping() {
+        assert_eq!(unescape("a &amp;&lt;&gt;&quot;&apos; b"), "a &<>\"' b");
+        assert_eq!(unescape("&#65;&#x42;&#X43;"), "AB&#X43;");
+        assert_eq!(unescape("R&B &unknown; &"), "R&B &unknown; &");
+    }
+
+    #[test]
+    fn resolving_against_the_playlist_directory() {
+        let playlist = Path::new("/music/Lists/mix.m3u");
+        let entry = |location: &str, uri: bool| Entry {
+            uri,
+            ..Entry::new(Path::new(location), None, None)
+        };
+
+        let resolve = |location, uri| entry(location, uri).resolve(playlist);
+        assert_eq!(
+            resolve("a.mp3", false),
+            Ok(PathBuf::from("/music/Lists/a.mp3"))
+        );
+        assert_eq!(
+            resolve("../Rock/./b.mp3", false),
+            Ok(PathBuf::from("/music/Rock/b.mp3"))
+        );
+        assert_eq!(
+            resolve("..\\Rock\\c.mp3", false),
+            Ok(PathBuf::from("/music/Rock/c.mp3"))
+        );
+        assert_eq!(
+            resolve("/elsewhere/d.mp3", false),
+            Ok(PathBuf::from("/elsewhere/d.mp3"))
+        );
+        assert_eq!(
+            resolve("file:///music/Tom%20&%20Jerry.mp3", false),
+            Ok(PathBuf::from("/music/Tom & Jerry.mp3"))
+        );
+        assert_eq!(
+            resolve("../Rock/e%20f.ogg", true),
+            Ok(PathBuf::from("/music/Rock/e f.ogg"))
+        );
+        assert!(resolve("http://example.com/stream.mp3", false).is_err());
+        assert!(resolve("https://example.com/g.mp3", true).is_err());
+    }
+}
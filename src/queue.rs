@@ -0,0 +1,400 @@
+use std::ops::Range;
+
+use rand::seq::SliceRandom;
+
+use crate::library::Track;
+
+/// The tracks the player goes through, in order.
+///
+/// Tracks that were already played stay in the queue, so the queue keeps
+/// track of both the current track and the one to play after it.
+/// When repeating, the queue starts over after its last track,
+/// reshuffling the tracks first when shuffled.
+#[derive(Debug, Default)]
+pub struct PlayQueue {
+    tracks: Vec<Track>,
+    current: Option<usize>,
+    next: usize,
+    repeat: bool,
+    shuffled: bool,
+    revision: u64,
+}
+
+impl PlayQueue {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn current(&self) -> Option<&Track> {
+        self.tracks.get(self.current?)
+    }
+
+    /// The index of the current track in the queue
+    pub fn current_index(&self) -> Option<usize> {
+        self.current
+    }
+
+    pub fn tracks(&self) -> &[Track] {
+        &self.tracks
+    }
+
+    pub fn len(&self) -> usize {
+        self.tracks.len()
+    }
+
+    pub fn repeat(&self) -> bool {
+        self.repeat
+    }
+
+    pub fn shuffled(&self) -> bool {
+        self.shuffled
+    }
+
+    /// Changes whenever tracks are added, removed or reordered
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    pub fn clear(&mut self) {
+        self.tracks.clear();
+        self.current = None;
+        self.next = 0;
+        self.revision += 1;
+    }
+
+    /// Replace the queue's tracks, starting again from the first one
+    pub fn replace(&mut self, tracks: Vec<Track>, shuffled: bool, repeat: bool) {
+        self.tracks = tracks;
+        self.restart(shuffled, repeat);
+    }
+
+    /// Go back to before the first track, with new playback options
+    pub fn restart(&mut self, shuffled: bool, repeat: bool) {
+        self.current = None;
+        self.next = 0;
+        self.shuffled = shuffled;
+        self.repeat = repeat;
+        self.revision += 1;
+        if shuffled {
+            self.tracks.shuffle(&mut rand::thread_rng());
+        }
+    }
+
+    /// Remove every track except the current one, which keeps playing
+    pub fn clear_all_but_current(&mut self) {
+        let Some(current) = self.current else {
+            return self.clear();
+        };
+        self.tracks.truncate(current + 1);
+        self.tracks.drain(..current);
+        self.current = Some(0);
+        self.next = 1;
+        self.revision += 1;
+    }
+
+    pub fn extend<I: IntoIterator<Item = Track>>(&mut self, tracks: I) {
+        self.tracks.extend(tracks);
+        self.revision += 1;
+    }
+
+    /// Add tracks to play right after the current one
+    pub fn insert_next<I: IntoIterator<Item = Track>>(&mut self, tracks: I) {
+        let at = self.next.min(self.tracks.len());
+        self.tracks.splice(at..at, tracks);
+        self.revision += 1;
+    }
+
+    /// Remove the tracks in `range`, which has to be within the queue.
+    /// Returns whether the current track was one of them,
+    /// in which case the queue goes on with the track that came after them.
+    pub fn remove(&mut self, range: Range<usize>) -> bool {
+        self.tracks.drain(range.clone());
+        self.revision += 1;
+
+        let shift = |index: usize| match index {
+            _ if index >= range.end => index - range.len(),
+            _ => index.min(range.start),
+        };
+        match self.current {
+            Some(current) if range.contains(&current) => {
+                self.current = None;
+                self.next = range.start;
+                true
+            }
+            current => {
+                self.current = current.map(shift);
+                self.next = shift(self.next);
+                false
+            }
+        }
+    }
+
+    /// Move the track at `from` so that it ends up at `to`, both within the queue.
+    /// The current track stays the current one wherever it ends up.
+    pub fn move_track(&mut self, from: usize, to: usize) {
+        let track = self.tracks.remove(from);
+        self.tracks.insert(to, track);
+        self.revision += 1;
+
+        let moved = |index: usize| match index {
+            _ if index == from => to,
+            _ if from < index && index <= to => index - 1,
+            _ if to <= index && index < from => index + 1,
+            _ => index,
+        };
+        match self.current {
+            Some(current) => {
+                self.current = Some(moved(current));
+                self.next = moved(current) + 1;
+            }
+            // The next track is the one to follow, unless the queue is already past its end
+            None if self.next < self.tracks.len() => self.next = moved(self.next),
+            None => {}
+        }
+    }
+
+    /// Shuffle the tracks that are still to be played
+    pub fn shuffle_upcoming(&mut self) {
+        let next = self.next.min(self.tracks.len());
+        self.tracks[next..].shuffle(&mut rand::thread_rng());
+        self.revision += 1;
+    }
+
+    /// Move on to the next track.
+    /// Returns `None` once the end of the queue is reached, unless repeating.
+    pub fn advance(&mut self) -> Option<&Track> {
+        if self.repeat && self.next >= self.tracks.len() {
+            self.restart(self.shuffled, self.repeat);
+        }
+
+        if self.next < self.tracks.len() {
+            self.current = Some(self.next);
+            self.next += 1;
+        } else {
+            self.current = None;
+        }
+
+        self.current()
+    }
+
+    /// Step back to the track before the current one, wrapping around when repeating.
+    /// Stays on the first track otherwise.
+    pub fn previous(&mut self) -> Option<&Track> {
+        let index = match self.current {
+            Some(0) if self.repeat => self.tracks.len() - 1,
+            Some(current) => current.saturating_sub(1),
+            // Past the end of the queue, go back to the last track played
+            None => self.next.checked_sub(1)?,
+        };
+        self.jump(index)
+    }
+
+    /// Make the track at `index` the current one, the queue then goes on from there.
+    /// Returns `None` if there's no such track.
+    pub fn jump(&mut self, index: usize) -> Option<&Track> {
+        if index >= self.tracks.len() {
+            return None;
+        }
+        self.current = Some(index);
+        self.next = index + 1;
+        self.current()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::time::SystemTime;
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::library::{FileStamp, Format, Tags};
+
+    fn track(name: &str) -> Track {
+        Track {
+            path: PathBuf::from(name),
+            format: Format::Wav,
+            duration: None,
+            tags: Tags::default(),
+            stamp: FileStamp {
+                size: 0,
+                modified: SystemTime::UNIX_EPOCH,
+            },
+        }
+    }
+
+    /// A queue of tracks named after the given letters, playing the one at `current`
+    fn queue(names: &str, current: Option<usize>) -> PlayQueue {
+        let mut queue = PlayQueue::new();
+        queue.replace(
+            names.chars().map(|c| track(&c.to_string())).collect(),
+            false,
+            false,
+        );
+        if let Some(current) = current {
+            queue.jump(current);
+        }
+        queue
+    }
+
+    fn names(queue: &PlayQueue) -> String {
+        let names = queue
+            .tracks()
+            .iter()
+            .map(|track| track.path.to_string_lossy());
+        names.collect()
+    }
+
+    /// The tracks' names, the current index and the name of the track that comes next
+    fn state(queue: &mut PlayQueue) -> (String, Option<usize>, Option<String>) {
+        let names = names(queue);
+        let current = queue.current_index();
+        let next = queue
+            .advance()
+            .map(|track| track.path.to_string_lossy().into_owned());
+        (names, current, next)
+    }
+
+    fn expect(
+        names: &str,
+        current: Option<usize>,
+        next: Option<&str>,
+    ) -> (String, Option<usize>, Option<String>) {
+        (names.to_owned(), current, next.map(str::to_owned))
+    }
+
+    #[test]
+    fn removing_around_the_current_track() {
+        let mut before = queue("abcdef", Some(3));
+        assert!(!before.remove(0..2));
+        assert_eq!(state(&mut before), expect("cdef", Some(1), Some("e")));
+
+        let mut after = queue("abcdef", Some(1));
+        assert!(!after.remove(3..5));
+        assert_eq!(state(&mut after), expect("abcf", Some(1), Some("c")));
+
+        let mut upcoming = queue("abcdef", Some(1));
+        assert!(!upcoming.remove(2..4));
+        assert_eq!(state(&mut upcoming), expect("abef", Some(1), Some("e")));
+    }
+
+    #[test]
+    fn removing_the_current_track_goes_on_after_it() {
+        let mut middle = queue("abcdef", Some(2));
+        assert!(middle.remove(1..4));
+        assert_eq!(state(&mut middle), expect("aef", None, Some("e")));
+
+        let mut last = queue("abc", Some(2));
+        assert!(last.remove(2..3));
+        assert_eq!(state(&mut last), expect("ab", None, None));
+    }
+
+    #[test]
+    fn removing_before_playing() {
+        let mut queue = queue("abcd", None);
+        queue.advance();
+        queue.advance();
+        queue.current = None;
+        // Stopped before b, with c to come next
+        assert!(!queue.remove(0..1));
+        assert_eq!(state(&mut queue), expect("bcd", None, Some("c")));
+    }
+
+    #[test]
+    fn moving_keeps_the_current_track() {
+        let mut moved = queue("abcde", Some(1));
+        moved.move_track(1, 4);
+        assert_eq!(state(&mut moved), expect("acdeb", Some(4), None));
+
+        let mut over = queue("abcde", Some(2));
+        over.move_track(0, 4);
+        assert_eq!(state(&mut over), expect("bcdea", Some(1), Some("d")));
+
+        let mut under = queue("abcde", Some(2));
+        under.move_track(4, 0);
+        assert_eq!(state(&mut under), expect("eabcd", Some(3), Some("d")));
+
+        let mut up_next = queue("abcde", Some(0));
+        up_next.move_track(4, 1);
+        assert_eq!(state(&mut up_next), expect("aebcd", Some(0), Some("e")));
+    }
+
+    #[test]
+    fn moving_without_a_current_track_keeps_the_next_one() {
+        let mut fresh = queue("abcde", None);
+        fresh.move_track(0, 3);
+        assert_eq!(state(&mut fresh), expect("bcdae", None, Some("a")));
+
+        let mut stopped = queue("abcde", Some(1));
+        stopped.current = None;
+        // Stopped with c to come next
+        stopped.move_track(4, 0);
+        assert_eq!(state(&mut stopped), expect("eabcd", None, Some("c")));
+
+        let mut finished = queue("abc", Some(2));
+        assert!(finished.advance().is_none());
+        finished.move_track(2, 0);
+        assert_eq!(state(&mut finished), expect("cab", None, None));
+    }
+
+    #[test]
+    fn inserting_after_the_current_track() {
+        let mut playing = queue("abc", Some(0));
+        playing.insert_next(vec![track("x"), track("y")]);
+        assert_eq!(state(&mut playing), expect("axybc", Some(0), Some("x")));
+
+        let mut fresh = queue("abc", None);
+        fresh.insert_next(vec![track("x")]);
+        assert_eq!(state(&mut fresh), expect("xabc", None, Some("x")));
+
+        let mut finished = queue("ab", Some(1));
+        assert!(finished.advance().is_none());
+        finished.insert_next(vec![track("x")]);
+        assert_eq!(state(&mut finished), expect("abx", None, Some("x")));
+    }
+
+    #[test]
+    fn shuffling_only_the_upcoming_tracks() {
+        let mut queue = queue("abcdefghij", Some(3));
+        queue.shuffle_upcoming();
+        let shuffled = names(&queue);
+        assert_eq!(&shuffled[..4], "abcd");
+        let mut upcoming: Vec<char> = shuffled[4..].chars().collect();
+        upcoming.sort();
+        assert_eq!(upcoming.into_iter().collect::<String>(), "efghij");
+        assert_eq!(queue.current_index(), Some(3));
+        assert_eq!(queue.current().unwrap().path, PathBuf::from("d"));
+    }
+
+    #[test]
+    fn clearing_all_but_the_current_track() {
+        let mut playing = queue("abcde", Some(2));
+        playing.clear_all_but_current();
+        assert_eq!(state(&mut playing), expect("c", Some(0), None));
+
+        let mut stopped = queue("abcde", None);
+        stopped.clear_all_but_current();
+        assert_eq!(state(&mut stopped), expect("", None, None));
+    }
+
+    #[test]
+    fn edits_change_the_revision() {
+        let mut queue = queue("abcde", Some(1));
+        let mut revision = queue.revision();
+        let edits: [fn(&mut PlayQueue); 5] = [
+            |queue| queue.insert_next(vec![track("x")]),
+            |queue| queue.move_track(0, 2),
+            |queue| {
+                queue.remove(3..4);
+            },
+            |queue| queue.shuffle_upcoming(),
+            |queue| queue.clear_all_but_current(),
+        ];
+        for edit in edits {
+            edit(&mut queue);
+            assert!(queue.revision() > revision);
+            revision = queue.revision();
+        }
+    }
+}
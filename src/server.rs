@@ -1,17 +1,33 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
+use std::time::Duration;
 
 use color_eyre::eyre::Result;
 use log::{error, info, warn};
+use rand::seq::SliceRandom;
+use rodio::Sink;
 
 use crate::cmdline;
 use crate::common::{
-    self, get_ws_builder, Address, ConnId, Message, Request, Response, ServerRequest, WSEvent,
+    self, get_ws_builder, Address, ConnId, ErrorCode, Event, EventKind, Hello, Message, Music,
+    PlaybackSpeed, PlaybackState, PlaylistEntry, PlaylistInfo, PlaylistReq, PlaylistResp,
+    QueuedResp, Request, RequestId, Response, SeekReq, ServerRequest, StatusResp, TrackInfo,
+    VolumeReq, VolumeResp, WSEvent,
 };
-use crate::error::AsEyreErrorResult;
+use crate::error::{AsEyreErrorResult, DoodleError};
+use crate::library::{self, Found, Library, Track, Watcher};
+use crate::output::{self, AudioOutput};
+use crate::playlist::{self, Entry};
+use crate::queue::PlayQueue;
+use crate::settings::{self, Settings};
+use crate::sources::{Decoded, Factor, Position, Ramped, Stretched, Tracked};
 
 pub trait ServerHandler {
-    fn on_open(&mut self, _: Address, _: ConnId);
+    fn on_open(&mut self, _: Address, _: ConnId, sender: &ws::Sender);
     fn on_remote_call(&mut self, _: Message, _: ConnId, sender: &ws::Sender);
     fn on_event(&mut self, _: ConnId, event: WSEvent, sender: &ws::Sender);
 }
@@ -23,6 +39,63 @@ struct IncomingComm {
     handler: HandlerDyn,
     sender: ws::Sender,
     shutdown: bool,
+    /// Whether the client introduced itself with a compatible `Hello`
+    greeted: bool,
+}
+
+impl IncomingComm {
+    fn reply_error(&self, id: Option<RequestId>, err: common::Error) {
+        warn!("{:?} - {}", ConnId(self.id), err);
+        let reply = Message::Response {
+            id,
+            response: Response::Error(err),
+        };
+        if let Err(err) = common::send_json_message(&reply, &self.sender) {
+            error!("{:?} - error {:?} sending response", ConnId(self.id), err);
+        }
+    }
+
+    fn on_hello(&mut self, hello: Hello) {
+        info!(
+            "{:?} - hello from {} ({}), protocol version {}, features {:?}",
+            ConnId(self.id),
+            hello.hostname,
+            hello.version,
+            hello.protocol_version,
+            hello.features
+        );
+
+        let local = Hello::local();
+        if !hello.is_compatible() {
+            let err = common::Error::new(
+                ErrorCode::Incompatible,
+                format!(
+                    "protocol version {} is not supported",
+                    hello.protocol_version
+                ),
+            )
+            .with_details(vec![format!(
+                "the server on {} ({}) speaks protocol version {}",
+                local.hostname, local.version, local.protocol_version
+            )]);
+            self.reply_error(None, err);
+            if let Err(err) = self.sender.close(ws::CloseCode::Protocol) {
+                error!("{:?} - error {:?} closing", ConnId(self.id), err);
+            }
+            return;
+        }
+
+        self.greeted = true;
+        if let Err(err) = common::send_json_message(&Message::Hello(local), &self.sender) {
+            error!("{:?} - error {:?} sending hello", ConnId(self.id), err);
+        }
+    }
+}
+
+/// Decode a message from a client, or find what to reject it with:
+/// the id of the request it seems to be, if any, and what's wrong with it
+fn decode_client_message(msg: &ws::Message) -> Result<Message, (Option<RequestId>, common::Error)> {
+    common::decode_message(msg).map_err(|err| (common::peek_request_id(msg), err))
 }
 
 impl ws::Handler for IncomingComm {
@@ -35,14 +108,33 @@ impl ws::Handler for IncomingComm {
                 .unwrap_or_else(|| "<unknown>".to_string()),
             port: 0,
         };
-        handler.on_open(address, ConnId(self.id));
+        handler.on_open(address, ConnId(self.id), &self.sender);
         Ok(())
     }
 
     fn on_message(&mut self, msg: ws::Message) -> ws::Result<()> {
-        let decoded_msg: Message = serde_json::from_str(&msg.to_string()).unwrap();
-        let mut handler = self.handler.lock().unwrap();
-        handler.on_remote_call(decoded_msg, ConnId(self.id), &self.sender);
+        let decoded_msg = match decode_client_message(&msg) {
+            Ok(decoded_msg) => decoded_msg,
+            Err((id, err)) => {
+                self.reply_error(id, err);
+                return Ok(());
+            }
+        };
+
+        match decoded_msg {
+            Message::Hello(hello) => self.on_hello(hello),
+            Message::Request { id, .. } if !self.greeted => {
+                let err = common::Error::new(
+                    ErrorCode::InvalidRequest,
+                    "a Hello is expected before any request",
+                );
+                self.reply_error(Some(id), err);
+            }
+            decoded_msg => {
+                let mut handler = self.handler.lock().unwrap();
+                handler.on_remote_call(decoded_msg, ConnId(self.id), &self.sender);
+            }
+        }
         Ok(())
     }
 
@@ -92,9 +184,54 @@ impl Connections {
     }
 }
 
+struct ClientConn {
+    sender: ws::Sender,
+    subscriptions: HashSet<EventKind>,
+}
+
+/// The connected clients, shared with the player thread so it can push events to them
+#[derive(Clone, Default)]
+pub struct Clients(Arc<Mutex<HashMap<ConnId, ClientConn>>>);
+
+impl Clients {
+    fn add(&self, conn_id: ConnId, sender: ws::Sender) {
+        let conn = ClientConn {
+            sender,
+            subscriptions: HashSet::new(),
+        };
+        self.0.lock().unwrap().insert(conn_id, conn);
+    }
+
+    fn remove(&self, conn_id: ConnId) {
+        self.0.lock().unwrap().remove(&conn_id);
+    }
+
+    /// Replace the events a client receives
+    fn subscribe(&self, conn_id: ConnId, events: Vec<EventKind>) {
+        if let Some(conn) = self.0.lock().unwrap().get_mut(&conn_id) {
+            conn.subscriptions = events.into_iter().collect();
+        }
+    }
+
+    /// Send an event to the clients subscribed to it
+    fn broadcast(&self, event: Event) {
+        let kind = event.kind();
+        let message = Message::Event(event);
+        for (conn_id, conn) in self.0.lock().unwrap().iter() {
+            if !conn.subscriptions.contains(&kind) {
+                continue;
+            }
+            if let Err(err) = common::send_json_message(&message, &conn.sender) {
+                error!("{:?} - error {:?} sending event", conn_id, err);
+            }
+        }
+    }
+}
+
 #[derive(Debug)]
 struct CallCompletion {
     conn_id: ConnId,
+    request_id: RequestId,
     sender: ws::Sender,
 }
 
@@ -125,9 +262,19 @@ impl ResponseWrapper {
 
 impl CallCompletion {
     fn complete(&self, resp: ResponseWrapper) {
-        match common::send_json_message(&Message::Response(resp.response), &self.sender) {
-            Err(err) => error!("{:?} - error {:?} sending response", self.conn_id, err),
-            _ => info!("{:?} - sent response to client", self.conn_id),
+        let message = Message::Response {
+            id: Some(self.request_id),
+            response: resp.response,
+        };
+        match common::send_json_message(&message, &self.sender) {
+            Err(err) => error!(
+                "{:?} - error {:?} sending response to {:?}",
+                self.conn_id, err, self.request_id
+            ),
+            _ => info!(
+                "{:?} - sent response to {:?} to client",
+                self.conn_id, self.request_id
+            ),
         }
 
         if resp.shutdown {
@@ -154,6 +301,7 @@ pub fn server_spawn(
             sender,
             handler: handler.clone(),
             shutdown: false,
+            greeted: false,
         }
     })?;
 
@@ -179,9 +327,69 @@ pub fn server_spawn(
     Ok((port, th))
 }
 
+/// How often the player thread wakes up to check on playback when idle
+const PLAYER_TICK: Duration = Duration::from_millis(100);
+
+/// How far into a track `Previous` restarts it rather than going to the previous one
+const PREVIOUS_RESTARTS_AFTER: Duration = Duration::from_secs(3);
+
+/// The playback speeds tracks can be played at
+const SPEEDS: std::ops::RangeInclusive<f32> = 0.5..=2.0;
+
+/// Tracks picked from the library, and why some of what was asked for was left out
+#[derive(Debug, Default)]
+struct Selection {
+    tracks: Vec<Track>,
+    skipped: Vec<String>,
+}
+
+impl From<Vec<Track>> for Selection {
+    fn from(tracks: Vec<Track>) -> Self {
+        Self {
+            tracks,
+            skipped: vec![],
+        }
+    }
+}
+
+/// What every track plays through, shared with the tracks' sources
+#[derive(Debug, Clone)]
+struct Effects {
+    /// Follows the settings' volume and mute
+    volume: Factor,
+    /// The speed to play at while preserving the pitch
+    stretch: Factor,
+    /// The speed the sinks play at, changing the pitch along with it
+    resample: Factor,
+}
+
+/// The player state that subscribed clients were last told about
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Published {
+    /// The number of tracks started so far, `None` while not playing any
+    track: Option<u64>,
+    state: PlaybackState,
+    queue: u64,
+    volume: u8,
+    muted: bool,
+    speed: PlaybackSpeed,
+}
+
 pub struct PlayerThread {
-    #[allow(dead_code)]
-    currently_playing: Option<String>,
+    library: Library,
+    watcher: Option<Watcher>,
+    clients: Clients,
+    queue: PlayQueue,
+    output: Box<dyn AudioOutput>,
+    sink: Option<Sink>,
+    position: Position,
+    effects: Effects,
+    speed: PlaybackSpeed,
+    settings: Settings,
+    settings_path: PathBuf,
+    playlists: playlist::Store,
+    plays: u64,
+    published: Published,
     receiver: mpsc::Receiver<ServerRequest>,
     #[allow(dead_code)]
     sender: mpsc::Sender<ServerRequest>,
@@ -190,11 +398,45 @@ pub struct PlayerThread {
 
 impl PlayerThread {
     pub fn new(
+        library: Library,
+        clients: Clients,
+        output: Box<dyn AudioOutput>,
+        settings_path: PathBuf,
+        playlists: playlist::Store,
         receiver: mpsc::Receiver<ServerRequest>,
         sender: mpsc::Sender<ServerRequest>,
     ) -> Self {
+        let watcher = Watcher::new(library.root())
+            .map_err(|err| warn!("Not watching the library for changes: {}", err))
+            .ok();
+        let settings = Settings::load(&settings_path);
+
         Self {
-            currently_playing: None,
+            library,
+            watcher,
+            clients,
+            queue: PlayQueue::new(),
+            output,
+            sink: None,
+            position: Position::default(),
+            effects: Effects {
+                volume: Factor::new(settings.effective_volume()),
+                stretch: Factor::new(1.0),
+                resample: Factor::new(1.0),
+            },
+            speed: PlaybackSpeed::default(),
+            plays: 0,
+            published: Published {
+                track: None,
+                state: PlaybackState::Stopped,
+                queue: 0,
+                volume: settings.volume,
+                muted: settings.muted,
+                speed: PlaybackSpeed::default(),
+            },
+            settings,
+            settings_path,
+            playlists,
             receiver,
             sender,
             shutdown: false,
@@ -206,34 +448,869 @@ impl PlayerThread {
             Request::Play(play_info) => {
                 self.play(play_info, call_completion);
             }
+            Request::Queue(queue_info) => {
+                self.enqueue(queue_info, false, call_completion);
+            }
+            Request::Pause => {
+                let state = self.set_paused(Some(true));
+                call_completion.complete(Response::PlaybackState(state).into());
+            }
+            Request::Resume => {
+                let state = self.set_paused(Some(false));
+                call_completion.complete(Response::PlaybackState(state).into());
+            }
+            Request::TogglePause => {
+                let state = self.set_paused(None);
+                call_completion.complete(Response::PlaybackState(state).into());
+            }
+            Request::Next => {
+                self.advance();
+                call_completion.complete(Response::Ok.into());
+            }
+            Request::Previous => {
+                self.previous();
+                call_completion.complete(Response::Ok.into());
+            }
+            Request::PlayIndex(index) => {
+                let response = match self.check_queue_index(index) {
+                    Ok(()) => {
+                        self.queue.jump(index);
+                        self.start_current();
+                        Response::Ok
+                    }
+                    Err(err) => Response::Error(err),
+                };
+                call_completion.complete(response.into());
+            }
+            Request::Seek(seek) => {
+                let response = self.seek(seek);
+                call_completion.complete(response.into());
+            }
+            Request::SetVolume(change) => {
+                let volume = match change {
+                    VolumeReq::To(volume) => volume,
+                    VolumeReq::Up(step) => self.settings.volume.saturating_add(step),
+                    VolumeReq::Down(step) => self.settings.volume.saturating_sub(step),
+                };
+                let response =
+                    self.set_volume(volume.min(settings::MAX_VOLUME), self.settings.muted);
+                call_completion.complete(Response::Volume(response).into());
+            }
+            Request::Mute | Request::Unmute | Request::ToggleMute => {
+                let muted = match request {
+                    Request::Mute => true,
+                    Request::Unmute => false,
+                    _ => !self.settings.muted,
+                };
+                let response = self.set_volume(self.settings.volume, muted);
+                call_completion.complete(Response::Volume(response).into());
+            }
+            Request::SetSpeed(speed) => {
+                let response = self.set_speed(speed);
+                call_completion.complete(response.into());
+            }
+            Request::Status => {
+                call_completion.complete(Response::Status(self.status()).into());
+            }
+            Request::Library => {
+                let tracks = self.library.tracks().map(TrackInfo::from).collect();
+                call_completion.complete(Response::Library(tracks).into());
+            }
+            Request::ListQueue => {
+                call_completion.complete(self.queue_list().into());
+            }
+            Request::RemoveFromQueue(range) => {
+                let response = self
+                    .remove_from_queue(range)
+                    .unwrap_or_else(Response::Error);
+                call_completion.complete(response.into());
+            }
+            Request::MoveInQueue { from, to } => {
+                let response = match self
+                    .check_queue_index(from)
+                    .and_then(|()| self.check_queue_index(to))
+                {
+                    Ok(()) => {
+                        self.queue.move_track(from, to);
+                        self.queue_list()
+                    }
+                    Err(err) => Response::Error(err),
+                };
+                call_completion.complete(response.into());
+            }
+            Request::ClearQueue => {
+                self.queue.clear_all_but_current();
+                call_completion.complete(self.queue_list().into());
+            }
+            Request::InsertNext(queue_info) => {
+                self.enqueue(queue_info, true, call_completion);
+            }
+            Request::ShuffleQueue => {
+                self.queue.shuffle_upcoming();
+                call_completion.complete(self.queue_list().into());
+            }
+            Request::Playlist(request) => {
+                let response = self
+                    .manage_playlist(request)
+                    .unwrap_or_else(Response::Error);
+                call_completion.complete(response.into());
+            }
+            Request::Rescan => {
+                let response = match self.library.rescan() {
+                    Ok(summary) => {
+                        self.on_library_changed(&summary);
+                        Response::Rescan(summary)
+                    }
+                    Err(err) => {
+                        error!("Rescan failed: {}", err);
+                        Response::Error(common::Error::new(
+                            ErrorCode::Internal,
+                            format!("rescan failed: {}", err),
+                        ))
+                    }
+                };
+                call_completion.complete(response.into());
+            }
+            Request::Subscribe(subscribe_info) => {
+                info!(
+                    "{:?} - subscribing to {:?}",
+                    call_completion.conn_id, subscribe_info.events
+                );
+                self.clients
+                    .subscribe(call_completion.conn_id, subscribe_info.events);
+                call_completion.complete(Response::Ok.into());
+            }
             Request::Shutdown => {
                 info!("Shutting down...");
                 self.shutdown = true;
+                self.stop();
                 call_completion.complete(ResponseWrapper::new(Response::Ok).with_shutdown());
             }
         }
     }
 
     fn play(&mut self, play_info: common::PlayReq, call_completion: CallCompletion) {
-        info!("TODO: play something");
-        drop(play_info);
-        call_completion.complete(Response::Ok.into());
+        let (tracks, skipped) = match play_info.music.as_ref().map(|music| self.select(music)) {
+            Some(Err(err)) => return call_completion.complete(Response::Error(err).into()),
+            Some(Ok(selection)) => (Some(selection.tracks), selection.skipped),
+            None => (None, vec![]),
+        };
+
+        if let Some(sink) = self.sink.take() {
+            sink.stop();
+        }
+        match tracks {
+            Some(tracks) => self
+                .queue
+                .replace(tracks, play_info.shuffled, play_info.repeat),
+            None => self.queue.restart(play_info.shuffled, play_info.repeat),
+        }
+        info!("Playing {} tracks", self.queue.len());
+
+        self.advance();
+        let response = match self.sink {
+            Some(_) => Response::Queued(QueuedResp {
+                tracks: self.queue.tracks().iter().map(TrackInfo::from).collect(),
+                skipped,
+            }),
+            None => Response::Error(common::Error::new(
+                ErrorCode::InvalidRequest,
+                "there is nothing to play",
+            )),
+        };
+        call_completion.complete(response.into());
+    }
+
+    /// Add music to the queue, at its end or right after the current track
+    fn enqueue(
+        &mut self,
+        queue_info: common::QueueReq,
+        next: bool,
+        call_completion: CallCompletion,
+    ) {
+        let Selection {
+            mut tracks,
+            skipped,
+        } = match self.select(&queue_info.music) {
+            Ok(selection) => selection,
+            Err(err) => return call_completion.complete(Response::Error(err).into()),
+        };
+        if queue_info.shuffled {
+            tracks.shuffle(&mut rand::thread_rng());
+        }
+        info!("Queueing {} tracks", tracks.len());
+
+        let queued = tracks.iter().map(TrackInfo::from).collect();
+        if next {
+            self.queue.insert_next(tracks);
+        } else {
+            self.queue.extend(tracks);
+        }
+        if self.sink.is_none() {
+            self.advance();
+        }
+
+        let response = QueuedResp {
+            tracks: queued,
+            skipped,
+        };
+        call_completion.complete(Response::Queued(response).into());
+    }
+
+    fn queue_list(&self) -> Response {
+        Response::QueueList(common::QueueListResp {
+            tracks: self.queue.tracks().iter().map(TrackInfo::from).collect(),
+            position: self.queue.current_index(),
+        })
+    }
+
+    fn check_queue_index(&self, index: usize) -> Result<(), common::Error> {
+        if index < self.queue.len() {
+            Ok(())
+        } else {
+            Err(common::Error::new(
+                ErrorCode::InvalidRequest,
+                format!(
+                    "index {} is past the end of the queue of {} tracks",
+                    index,
+                    self.queue.len()
+                ),
+            ))
+        }
+    }
+
+    /// Remove tracks from the queue, moving on to the next track if the current one was removed
+    fn remove_from_queue(&mut self, range: Range<usize>) -> Result<Response, common::Error> {
+        if range.is_empty() {
+            return Err(common::Error::new(
+                ErrorCode::InvalidRequest,
+                format!("no tracks to remove in {:?}", range),
+            ));
+        }
+        self.check_queue_index(range.end - 1)?;
+
+        info!("Removing {} tracks from the queue", range.len());
+        if self.queue.remove(range) {
+            self.skip_current();
+        }
+        Ok(self.queue_list())
+    }
+
+    /// Fade to a new volume and keep it for the next runs
+    fn set_volume(&mut self, volume: u8, muted: bool) -> VolumeResp {
+        self.settings.volume = volume;
+        self.settings.muted = muted;
+        self.effects.volume.set(self.settings.effective_volume());
+        if let Err(err) = self.settings.save(&self.settings_path) {
+            warn!(
+                "Failed saving settings to {:?}: {}",
+                self.settings_path, err
+            );
+        }
+
+        VolumeResp { volume, muted }
+    }
+
+    fn set_speed(&mut self, speed: PlaybackSpeed) -> Response {
+        if !SPEEDS.contains(&speed.speed) {
+            return Response::Error(common::Error::new(
+                ErrorCode::InvalidRequest,
+                format!(
+                    "the speed must be between {} and {}",
+                    SPEEDS.start(),
+                    SPEEDS.end()
+                ),
+            ));
+        }
+
+        let (stretch, resample) = if speed.preserve_pitch {
+            (speed.speed, 1.0)
+        } else {
+            (1.0, speed.speed)
+        };
+        self.effects.stretch.set(stretch);
+        self.effects.resample.set(resample);
+        if let Some(sink) = &self.sink {
+            sink.set_speed(resample);
+        }
+        self.speed = speed;
+        Response::Speed(speed)
+    }
+
+    /// Restart the current track somewhere else within it, keeping it paused if it was.
+    /// Seeking to its end goes on with the next track.
+    fn seek(&mut self, seek: SeekReq) -> Response {
+        let (paused, track) = match (&self.sink, self.queue.current()) {
+            (Some(sink), Some(track)) => (sink.is_paused(), track),
+            _ => {
+                return Response::Error(common::Error::new(
+                    ErrorCode::InvalidRequest,
+                    "nothing is playing",
+                ))
+            }
+        };
+        let Some(target) = seek_target(self.position.get(), seek, track.duration) else {
+            info!("Seeked past the end of {}", track.display_name());
+            self.skip_current();
+            return Response::Ok;
+        };
+
+        // The old source may still be updating its position until it's dropped
+        let position = Position::default();
+        let started = Self::start_track(
+            self.output.as_ref(),
+            self.library.root(),
+            track,
+            position.clone(),
+            &self.effects,
+            target,
+        );
+        match started {
+            Ok((sink, reached)) => {
+                info!("Seeked to {:?} in {}", reached, track.display_name());
+                if paused {
+                    sink.pause();
+                }
+                self.sink = Some(sink);
+                self.position = position;
+                self.clients.broadcast(Event::Seeked(reached));
+                Response::Seeked(reached)
+            }
+            Err(err) => {
+                warn!("Failed seeking in {:?}: {}", track.path, err);
+                Response::Error(common::Error::new(
+                    ErrorCode::Internal,
+                    format!("failed seeking: {}", err),
+                ))
+            }
+        }
+    }
+
+    /// Go back to the previous track, or to the start of the current one
+    /// if it's been playing for a while
+    fn previous(&mut self) {
+        if self.sink.is_some() && self.position.get() > PREVIOUS_RESTARTS_AFTER {
+            info!("Restarting the current track");
+        } else {
+            self.queue.previous();
+        }
+        self.start_current();
+    }
+
+    /// Pause or resume the current track, `None` toggles between the two
+    fn set_paused(&mut self, paused: Option<bool>) -> PlaybackState {
+        if let Some(sink) = &self.sink {
+            match paused.unwrap_or(!sink.is_paused()) {
+                true => sink.pause(),
+                false => sink.play(),
+            }
+        }
+
+        let state = self.playback_state();
+        info!("Playback is {:?}", state);
+        state
+    }
+
+    fn status(&self) -> StatusResp {
+        StatusResp {
+            state: self.playback_state(),
+            track: self.queue.current().map(TrackInfo::from),
+            position: self.sink.as_ref().map(|_| self.position.get()),
+            volume: self.settings.volume,
+            muted: self.settings.muted,
+            speed: self.speed,
+            repeat: self.queue.repeat(),
+            shuffled: self.queue.shuffled(),
+            queue_position: self.queue.current_index(),
+            queue_length: self.queue.len(),
+        }
+    }
+
+    fn playback_state(&self) -> PlaybackState {
+        match &self.sink {
+            None => PlaybackState::Stopped,
+            Some(sink) if sink.is_paused() => PlaybackState::Paused,
+            Some(_) => PlaybackState::Playing,
+        }
+    }
+
+    /// Find the tracks for a selection of music in the library.
+    /// Fails if any of the selected songs can't be found, or could be one of several tracks.
+    fn select(&self, music: &Music) -> Result<Selection, common::Error> {
+        match music {
+            Music::Songs(songs) => {
+                let mut tracks = vec![];
+                let mut missing = vec![];
+                for song in songs {
+                    match self.library.search(song) {
+                        Found::Tracks(found) if found.is_empty() => {
+                            missing.push(format!("song not found: {}", song));
+                        }
+                        Found::Tracks(found) => tracks.extend(found.into_iter().cloned()),
+                        Found::Ambiguous(candidates) => {
+                            return Err(common::Error::new(
+                                ErrorCode::InvalidRequest,
+                                format!(
+                                    "{} is ambiguous, it matches {} songs",
+                                    song,
+                                    candidates.len()
+                                ),
+                            )
+                            .with_details(
+                                candidates
+                                    .iter()
+                                    .map(|track| track.path.display().to_string())
+                                    .collect(),
+                            ))
+                        }
+                    }
+                }
+
+                if missing.is_empty() {
+                    Ok(tracks.into())
+                } else {
+                    Err(common::Error::new(
+                        ErrorCode::NotFound,
+                        format!("{} of {} songs not found", missing.len(), songs.len()),
+                    )
+                    .with_details(missing))
+                }
+            }
+            Music::Playlist(playlist) => self.select_playlist(playlist),
+            Music::AllSongs => Ok(self.library.tracks().cloned().collect::<Vec<_>>().into()),
+        }
+    }
+
+    /// Find the tracks of a playlist file in the library.
+    /// Entries that can't be played are skipped, unless none of them can.
+    fn select_playlist(&self, name: &str) -> Result<Selection, common::Error> {
+        let root = self.library_root();
+        // The playlists made through the server come first, the library may not even see them
+        let full_path = match self.playlists.find(name) {
+            Some(full_path) => full_path,
+            None => root.join(self.library.find_playlist(name).ok_or_else(|| {
+                common::Error::new(ErrorCode::NotFound, format!("playlist not found: {}", name))
+            })?),
+        };
+        let path = full_path.strip_prefix(&root).unwrap_or(&full_path);
+        let entries = playlist::read(&full_path).map_err(|err| {
+            common::Error::new(
+                ErrorCode::Internal,
+                format!("failed reading playlist {:?}: {}", path, err),
+            )
+        })?;
+
+        let mut selection = Selection::default();
+        for entry in &entries {
+            let problem = match self.resolve_entry(&root, &full_path, entry) {
+                Ok(track) => {
+                    selection.tracks.push(track.clone());
+                    continue;
+                }
+                Err(problem) => problem,
+            };
+
+            let title = entry
+                .title
+                .as_ref()
+                .map(|title| format!(" ({})", title))
+                .unwrap_or_default();
+            selection.skipped.push(format!(
+                "{}:{}: {}{}: {}",
+                path.display(),
+                entry.line,
+                entry.location,
+                title,
+                problem
+            ));
+        }
+
+        if entries.is_empty() {
+            return Err(common::Error::new(
+                ErrorCode::InvalidRequest,
+                format!("playlist {:?} is empty", path),
+            ));
+        }
+        if selection.tracks.is_empty() {
+            return Err(common::Error::new(
+                ErrorCode::NotFound,
+                format!(
+                    "none of the {} entries of playlist {:?} can be played",
+                    entries.len(),
+                    path
+                ),
+            )
+            .with_details(selection.skipped));
+        }
+        if !selection.skipped.is_empty() {
+            warn!(
+                "Skipping {} broken entries of playlist {:?}",
+                selection.skipped.len(),
+                path
+            );
+        }
+        Ok(selection)
+    }
+
+    /// The library root as an absolute path. Entries given as absolute paths or URLs
+    /// can only be matched with the library's tracks through an absolute root.
+    fn library_root(&self) -> PathBuf {
+        fs::canonicalize(self.library.root()).unwrap_or_else(|_| self.library.root().to_owned())
+    }
+
+    /// Find the track a playlist entry points to, or say why it can't be played
+    fn resolve_entry(&self, root: &Path, playlist: &Path, entry: &Entry) -> Result<&Track, String> {
+        let resolved = entry.resolve(playlist)?;
+        let relative = resolved
+            .strip_prefix(root)
+            .map_err(|_| "outside the music library".to_owned())?;
+        match self.library.track(relative) {
+            Some(track) => Ok(track),
+            None if resolved.is_file() => Err("not a playable track".to_owned()),
+            None => Err("file not found".to_owned()),
+        }
+    }
+
+    fn manage_playlist(&mut self, request: PlaylistReq) -> Result<Response, common::Error> {
+        match request {
+            PlaylistReq::List => {
+                let names = self.playlists.names().map_err(|err| {
+                    common::Error::new(
+                        ErrorCode::Internal,
+                        format!("failed listing playlists: {}", err),
+                    )
+                })?;
+                let mut playlists = vec![];
+                for name in names {
+                    match self.load_playlist(&name) {
+                        Ok((name, entries)) => playlists.push(PlaylistInfo {
+                            name,
+                            length: entries.len(),
+                        }),
+                        Err(err) => warn!("Not listing playlist {:?}: {}", name, err),
+                    }
+                }
+                Ok(Response::Playlists(playlists))
+            }
+            PlaylistReq::Show(name) => {
+                let (name, entries) = self.load_playlist(&name)?;
+                Ok(self.describe_playlist(name, &entries, vec![]))
+            }
+            PlaylistReq::Create(name) => {
+                let name = self.check_new_playlist(&name, false)?;
+                self.save_playlist(&name, &[])?;
+                info!("Created playlist {:?}", name);
+                Ok(self.describe_playlist(name, &[], vec![]))
+            }
+            PlaylistReq::Add { name, music } => {
+                let (name, mut entries) = self.load_playlist(&name)?;
+                let Selection { tracks, skipped } = self.select(&music)?;
+                let root = self.library_root();
+                entries.extend(tracks.iter().map(|track| {
+                    Entry::new(
+                        &root.join(&track.path),
+                        Some(track.display_name()),
+                        track.duration,
+                    )
+                }));
+                self.save_playlist(&name, &entries)?;
+                info!("Added {} tracks to playlist {:?}", tracks.len(), name);
+                Ok(self.describe_playlist(name, &entries, skipped))
+            }
+            PlaylistReq::Remove { name, index } => {
+                let (name, mut entries) = self.load_playlist(&name)?;
+                check_playlist_index(&name, &entries, index)?;
+                entries.remove(index);
+                self.save_playlist(&name, &entries)?;
+                Ok(self.describe_playlist(name, &entries, vec![]))
+            }
+            PlaylistReq::Move { name, from, to } => {
+                let (name, mut entries) = self.load_playlist(&name)?;
+                check_playlist_index(&name, &entries, from)?;
+                check_playlist_index(&name, &entries, to)?;
+                let entry = entries.remove(from);
+                entries.insert(to, entry);
+                self.save_playlist(&name, &entries)?;
+                Ok(self.describe_playlist(name, &entries, vec![]))
+            }
+            PlaylistReq::SaveQueue { name, replace } => {
+                let name = self.check_new_playlist(&name, replace)?;
+                let root = self.library_root();
+                let entries: Vec<_> = self
+                    .queue
+                    .tracks()
+                    .iter()
+                    .map(|track| {
+                        Entry::new(
+                            &root.join(&track.path),
+                            Some(track.display_name()),
+                            track.duration,
+                        )
+                    })
+                    .collect();
+                self.save_playlist(&name, &entries)?;
+                info!("Saved the queue as playlist {:?}", name);
+                Ok(self.describe_playlist(name, &entries, vec![]))
+            }
+            PlaylistReq::Delete(name) => {
+                let (name, _) = self.load_playlist(&name)?;
+                self.playlists.delete(&name).map_err(|err| {
+                    common::Error::new(
+                        ErrorCode::Internal,
+                        format!("failed deleting playlist {}: {}", name, err),
+                    )
+                })?;
+                info!("Deleted playlist {:?}", name);
+                Ok(Response::Ok)
+            }
+        }
+    }
+
+    /// Make sure a playlist can be made with the name `name`,
+    /// returns the name to save it under
+    fn check_new_playlist(&self, name: &str, replace: bool) -> Result<String, common::Error> {
+        match self.playlists.stored_name(name) {
+            Some(_) if !replace => Err(common::Error::new(
+                ErrorCode::InvalidRequest,
+                format!("playlist already exists: {}", name),
+            )),
+            Some(stored) => Ok(stored),
+            None if self.playlists.path(name).is_none() => Err(common::Error::new(
+                ErrorCode::InvalidRequest,
+                format!("invalid playlist name: {:?}", name),
+            )),
+            None => Ok(name.to_owned()),
+        }
+    }
+
+    /// Read the entries of a playlist, along with the name it's stored under
+    fn load_playlist(&self, name: &str) -> Result<(String, Vec<Entry>), common::Error> {
+        let not_found =
+            || common::Error::new(ErrorCode::NotFound, format!("playlist not found: {}", name));
+        let name = self.playlists.stored_name(name).ok_or_else(not_found)?;
+        let path = self.playlists.find(&name).ok_or_else(not_found)?;
+        let entries = playlist::read(&path).map_err(|err| {
+            common::Error::new(
+                ErrorCode::Internal,
+                format!("failed reading playlist {}: {}", name, err),
+            )
+        })?;
+        Ok((name, entries))
+    }
+
+    fn save_playlist(&self, name: &str, entries: &[Entry]) -> Result<(), common::Error> {
+        self.playlists.save(name, entries).map_err(|err| {
+            common::Error::new(
+                ErrorCode::Internal,
+                format!("failed saving playlist {}: {}", name, err),
+            )
+        })
+    }
+
+    /// Respond with a playlist's entries and the tracks they point to
+    fn describe_playlist(&self, name: String, entries: &[Entry], skipped: Vec<String>) -> Response {
+        let root = self.library_root();
+        let path = self.playlists.find(&name).unwrap_or_default();
+        let entries = entries
+            .iter()
+            .map(|entry| {
+                let (track, problem) = match self.resolve_entry(&root, &path, entry) {
+                    Ok(track) => (Some(TrackInfo::from(track)), None),
+                    Err(problem) => (None, Some(problem)),
+                };
+                PlaylistEntry {
+                    location: entry.location.clone(),
+                    title: entry.title.clone(),
+                    track,
+                    problem,
+                }
+            })
+            .collect();
+        Response::Playlist(PlaylistResp {
+            name,
+            entries,
+            skipped,
+        })
+    }
+
+    /// Stop playing and empty the queue
+    fn stop(&mut self) {
+        self.queue.clear();
+        if let Some(sink) = self.sink.take() {
+            sink.stop();
+        }
+    }
+
+    /// Start playing the next track in the queue that can be decoded
+    fn advance(&mut self) {
+        self.queue.advance();
+        self.start_current();
+    }
+
+    /// Go on with the next track, without starting to play it if paused
+    fn skip_current(&mut self) {
+        let paused = self.sink.as_ref().is_some_and(Sink::is_paused);
+        self.advance();
+        if paused {
+            self.set_paused(Some(true));
+        }
+    }
+
+    /// Start playing the current track of the queue from its beginning.
+    /// Tracks that fail to open are logged and skipped.
+    fn start_current(&mut self) {
+        self.sink = None;
+
+        // A repeating queue never ends, so give up once every track failed
+        let mut failures = 0;
+        while let Some(track) = self.queue.current() {
+            self.position = Position::default();
+            let started = Self::start_track(
+                self.output.as_ref(),
+                self.library.root(),
+                track,
+                self.position.clone(),
+                &self.effects,
+                Duration::ZERO,
+            );
+            match started {
+                Ok((sink, _)) => {
+                    info!("Now playing {}", track.display_name());
+                    self.sink = Some(sink);
+                    self.plays += 1;
+                    return;
+                }
+                Err(err) => {
+                    warn!("Skipping {:?}: {}", track.path, err);
+                    failures += 1;
+                    if failures >= self.queue.len() {
+                        warn!("None of the queued tracks can be played");
+                        return;
+                    }
+                }
+            }
+            self.queue.advance();
+        }
+    }
+
+    /// Start playing `track` from `start` into it.
+    /// Returns the sink playing it and where playback actually started.
+    fn start_track(
+        output: &dyn AudioOutput,
+        root: &Path,
+        track: &Track,
+        position: Position,
+        effects: &Effects,
+        start: Duration,
+    ) -> Result<(Sink, Duration)> {
+        let mut source = Decoded::open(&root.join(&track.path))?;
+        let start = if start.is_zero() {
+            start
+        } else {
+            source.seek(start)?
+        };
+        let sink = output.new_sink()?;
+        let source = Tracked::new(source, position, start);
+        let source = Stretched::new(source, effects.stretch.clone());
+        sink.append(Ramped::new(source, effects.volume.clone()));
+        sink.set_speed(effects.resample.get());
+        Ok((sink, start))
+    }
+
+    fn on_library_changed(&self, summary: &common::RescanResp) {
+        if summary.changed() {
+            self.clients
+                .broadcast(Event::LibraryChanged(summary.clone()));
+        }
+    }
+
+    /// Tell subscribed clients what changed since the last time
+    fn publish_changes(&mut self) {
+        let status = self.status();
+        let published = Published {
+            track: self.sink.as_ref().map(|_| self.plays),
+            state: status.state,
+            queue: self.queue.revision(),
+            volume: status.volume,
+            muted: status.muted,
+            speed: status.speed,
+        };
+        let previous = std::mem::replace(&mut self.published, published);
+
+        if previous.track != published.track {
+            self.clients
+                .broadcast(Event::TrackChanged(published.track.and(status.track)));
+        }
+        if previous.state != published.state {
+            self.clients
+                .broadcast(Event::PlaybackStateChanged(published.state));
+        }
+        if previous.queue != published.queue {
+            self.clients.broadcast(Event::QueueChanged {
+                length: status.queue_length,
+                position: status.queue_position,
+            });
+        }
+        if previous.volume != published.volume {
+            self.clients
+                .broadcast(Event::VolumeChanged(published.volume));
+        }
+        if previous.muted != published.muted {
+            self.clients.broadcast(Event::MuteChanged(published.muted));
+        }
+        if previous.speed != published.speed {
+            self.clients.broadcast(Event::SpeedChanged(published.speed));
+        }
+    }
+
+    /// Periodic upkeep - pick up library changes,
+    /// and move on to the next song when the current one ends
+    fn on_tick(&mut self) {
+        if let Some(changed) = self.watcher.as_mut().and_then(Watcher::poll) {
+            let summary = self.library.update(changed);
+            self.on_library_changed(&summary);
+        }
+
+        if self.sink.as_ref().is_some_and(Sink::empty) {
+            info!(
+                "Finished playing {:?}",
+                self.queue.current().map(Track::display_name)
+            );
+            self.advance();
+        }
+
+        self.publish_changes();
     }
 
     pub fn run(&mut self) {
         let mut ws_sender = None;
-        while let Ok(ServerRequest(request, conn_id, sender)) = self.receiver.recv() {
-            info!("{:?} - {:?}", conn_id, request);
+        loop {
+            let ServerRequest(request_id, request, conn_id, sender) =
+                match self.receiver.recv_timeout(PLAYER_TICK) {
+                    Ok(server_request) => server_request,
+                    Err(mpsc::RecvTimeoutError::Timeout) => {
+                        self.on_tick();
+                        continue;
+                    }
+                    Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                };
+            info!("{:?} - {:?} {:?}", conn_id, request_id, request);
             if ws_sender.is_none() {
                 ws_sender = Some(sender.clone());
             }
-            self.on_remote_call(request, CallCompletion { conn_id, sender });
+            let call_completion = CallCompletion {
+                conn_id,
+                request_id,
+                sender,
+            };
+            self.on_remote_call(request, call_completion);
+            self.publish_changes();
             if self.shutdown {
                 std::thread::sleep(std::time::Duration::from_millis(1)); // Prevent Abnormal close on the client's side
-                let _ = ws_sender
-                    .take()
-                    .map(|sender| sender.shutdown())
-                    .expect("shutdown without any connection");
+                let sender = ws_sender.take().expect("shutdown without any connection");
+                if let Err(err) = sender.shutdown() {
+                    error!("error {:?} shutting down", err);
+                }
                 break;
             }
         }
@@ -242,46 +1319,147 @@ impl PlayerThread {
     }
 }
 
+/// Where a seek from `position` lands, or `None` if that's the end of the track or past it
+fn seek_target(position: Duration, seek: SeekReq, duration: Option<Duration>) -> Option<Duration> {
+    let target = match seek {
+        SeekReq::To(target) => target,
+        SeekReq::Forward(offset) => position.saturating_add(offset),
+        SeekReq::Backward(offset) => position.saturating_sub(offset),
+    };
+    match duration {
+        Some(duration) if target >= duration => None,
+        _ => Some(target),
+    }
+}
+
+/// The path given with `--<option>`, or else the default one, which needs a home directory
+fn path_or_default(
+    path: Option<PathBuf>,
+    default: fn() -> Option<PathBuf>,
+    option: &str,
+) -> Result<PathBuf> {
+    path.or_else(default)
+        .ok_or_else(|| {
+            DoodleError::Generic(format!(
+                "no home directory for the default --{} path, please give one",
+                option
+            ))
+        })
+        .as_eyre_result()
+}
+
+fn check_playlist_index(name: &str, entries: &[Entry], index: usize) -> Result<(), common::Error> {
+    if index < entries.len() {
+        Ok(())
+    } else {
+        Err(common::Error::new(
+            ErrorCode::InvalidRequest,
+            format!(
+                "index {} is past the end of playlist {} of {} tracks",
+                index,
+                name,
+                entries.len()
+            ),
+        ))
+    }
+}
+
 pub struct Server {
-    #[allow(dead_code)]
-    path: std::path::PathBuf,
+    clients: Clients,
     sender: mpsc::Sender<ServerRequest>,
-    _thread: thread::JoinHandle<()>,
+    thread: Option<thread::JoinHandle<()>>,
 }
 
 impl Server {
-    pub fn new(path: std::path::PathBuf) -> Self {
+    pub fn new(command: cmdline::Server) -> Result<Self> {
         let (tx, rx) = mpsc::channel();
+        let (init_tx, init_rx) = mpsc::channel();
+        let cache_path = path_or_default(
+            command.library_cache,
+            library::default_cache_path,
+            "library-cache",
+        )?;
+        let settings_path = path_or_default(command.settings, settings::default_path, "settings")?;
+        let playlists = playlist::Store::new(path_or_default(
+            command.playlists,
+            playlist::default_store_path,
+            "playlists",
+        )?);
+        let library = Library::open(command.path, cache_path)?;
+        let output_kind = command.output;
+        let clients = Clients::default();
+        let player_clients = clients.clone();
 
-        Self {
-            path,
-            sender: tx.clone(),
-            _thread: thread::Builder::new()
-                .name("player".to_owned())
-                .spawn(move || {
-                    let mut inner = PlayerThread::new(rx, tx.clone());
-                    inner.run()
-                })
-                .as_eyre_result()
-                .expect("failed to start player thread"),
+        let player_tx = tx.clone();
+        let thread = thread::Builder::new()
+            .name("player".to_owned())
+            .spawn(move || {
+                // The output is not `Send`, so it has to be created on the player's thread
+                match output::open(&output_kind) {
+                    Ok(output) => {
+                        let _ = init_tx.send(Ok(()));
+                        PlayerThread::new(
+                            library,
+                            player_clients,
+                            output,
+                            settings_path,
+                            playlists,
+                            rx,
+                            player_tx,
+                        )
+                        .run()
+                    }
+                    Err(err) => {
+                        let _ = init_tx.send(Err(err));
+                    }
+                }
+            })
+            .as_eyre_result()?;
+
+        init_rx.recv().as_eyre_result()??;
+
+        Ok(Self {
+            clients,
+            sender: tx,
+            thread: Some(thread),
+        })
+    }
+
+    /// Wait for the player thread to finish, making sure the output is flushed
+    pub fn join(&mut self) {
+        if let Some(th) = self.thread.take() {
+            if let Err(panic) = th.join() {
+                std::panic::resume_unwind(panic);
+            }
         }
     }
 }
 
 impl ServerHandler for Server {
-    fn on_open(&mut self, address: Address, conn_id: ConnId) {
+    fn on_open(&mut self, address: Address, conn_id: ConnId, sender: &ws::Sender) {
         info!("{:?} - open from {:?}", conn_id, address);
+        self.clients.add(conn_id, sender.clone());
     }
 
     fn on_remote_call(&mut self, msg: Message, conn_id: ConnId, sender: &ws::Sender) {
         match msg {
-            Message::Request(req) => {
-                self.sender
-                    .send(ServerRequest(req, conn_id, sender.clone()))
-                    .as_eyre_result()
-                    .unwrap();
+            Message::Request { id, request } => {
+                let request = ServerRequest(id, request, conn_id, sender.clone());
+                if self.sender.send(request).is_err() {
+                    error!("{:?} - the player is gone, failing {:?}", conn_id, id);
+                    let reply = Message::Response {
+                        id: Some(id),
+                        response: Response::Error(common::Error::new(
+                            ErrorCode::Internal,
+                            "the player is not running",
+                        )),
+                    };
+                    if let Err(err) = common::send_json_message(&reply, sender) {
+                        error!("{:?} - error {:?} sending response", conn_id, err);
+                    }
+                }
             }
-            Message::Response(..) => {
+            Message::Hello(..) | Message::Response { .. } | Message::Event(..) => {
                 warn!("{:?} - Ignoring unexpected {:?}", conn_id, msg);
             }
         }
@@ -297,6 +1475,10 @@ impl ServerHandler for Server {
             }
         }
 
+        if let WSEvent::Shutdown | WSEvent::Close(..) | WSEvent::Error(..) = event {
+            self.clients.remove(conn_id);
+        }
+
         // info!("shutting down after last client event");
         // let _ = sender.shutdown();
     }
@@ -305,12 +1487,83 @@ impl ServerHandler for Server {
 pub(crate) fn main(command: cmdline::Server, address: Address) -> Result<()> {
     info!("running {:?} as server on {}", command, address);
 
-    let server = Arc::new(Mutex::new(Server::new(command.path)));
+    let server = Arc::new(Mutex::new(Server::new(command)?));
 
-    let (_, th) = server_spawn(&address, server)?;
+    let (_, th) = server_spawn(&address, server.clone())?;
 
-    match th.join() {
+    let result = match th.join() {
         Ok(result) => result,
         Err(panic) => std::panic::resume_unwind(panic),
+    };
+
+    if result.is_ok() {
+        server.lock().unwrap().join();
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn seeking_within_the_track() {
+        let position = Duration::from_secs(30);
+        let duration = Some(Duration::from_secs(180));
+        let seek = |seek, duration| seek_target(position, seek, duration);
+
+        assert_eq!(
+            seek(SeekReq::Forward(Duration::from_secs(10)), duration),
+            Some(Duration::from_secs(40))
+        );
+        assert_eq!(
+            seek(SeekReq::Backward(Duration::from_secs(10)), duration),
+            Some(Duration::from_secs(20))
+        );
+        assert_eq!(
+            seek(SeekReq::Backward(Duration::MAX), duration),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(
+            seek(SeekReq::To(Duration::from_secs(179)), duration),
+            Some(Duration::from_secs(179))
+        );
+    }
+
+    #[test]
+    fn seeking_to_the_end_or_past_it() {
+        let position = Duration::from_secs(30);
+        let duration = Some(Duration::from_secs(180));
+        let seek = |seek, duration| seek_target(position, seek, duration);
+
+        assert_eq!(seek(SeekReq::To(Duration::from_secs(180)), duration), None);
+        assert_eq!(seek(SeekReq::To(Duration::from_secs(600)), duration), None);
+        assert_eq!(
+            seek(SeekReq::Forward(Duration::from_secs(150)), duration),
+            None
+        );
+        assert_eq!(seek(SeekReq::Forward(Duration::MAX), duration), None);
+        // Without a known length, it's up to the decoder
+        assert_eq!(
+            seek(SeekReq::Forward(Duration::MAX), None),
+            Some(Duration::MAX)
+        );
+    }
+
+    #[test]
+    fn binary_frames_are_rejected_without_an_id() {
+        let (id, err) = decode_client_message(&ws::Message::binary(vec![1, 2, 3])).unwrap_err();
+        assert_eq!(id, None);
+        assert_eq!(err.code, ErrorCode::Malformed);
+    }
+
+    #[test]
+    fn unknown_requests_are_rejected_with_their_id() {
+        let msg = ws::Message::text(r#"{"Request": {"id": 3, "request": "Dance"}}"#);
+        let (id, err) = decode_client_message(&msg).unwrap_err();
+        assert_eq!(id, Some(RequestId(3)));
+        assert_eq!(err.code, ErrorCode::Malformed);
     }
 }
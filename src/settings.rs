@@ -0,0 +1,77 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::Result;
+use log::{info, warn};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::common;
+
+/// Where the player's settings are kept by default, in the user's config directory
+pub fn default_path() -> Option<PathBuf> {
+    Some(
+        dirs::config_dir()?
+            .join(common::APP_DIR_NAME)
+            .join("settings.json"),
+    )
+}
+
+/// The loudest volume percentage, the track's own volume
+pub const MAX_VOLUME: u8 = 100;
+
+/// Player settings that outlive the server process
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    /// Volume percentage, 100 being the track's own volume
+    pub volume: u8,
+    pub muted: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            volume: 100,
+            muted: false,
+        }
+    }
+}
+
+impl Settings {
+    /// Load the settings saved at `path`, falling back to the defaults
+    pub fn load(path: &Path) -> Self {
+        let loaded: Result<Self> = File::open(path)
+            .map_err(Into::into)
+            .and_then(|file| Ok(serde_json::from_reader(BufReader::new(file))?));
+        match loaded {
+            Ok(settings) => {
+                info!("Loaded settings from {:?}", path);
+                Self {
+                    volume: settings.volume.min(MAX_VOLUME),
+                    ..settings
+                }
+            }
+            Err(err) => {
+                warn!(
+                    "Using default settings, not loaded from {:?}: {}",
+                    path, err
+                );
+                Self::default()
+            }
+        }
+    }
+
+    /// The factor to scale samples by
+    pub fn effective_volume(&self) -> f32 {
+        if self.muted {
+            0.0
+        } else {
+            self.volume as f32 / 100.0
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        common::atomic_write(path, &serde_json::to_vec_pretty(self)?)
+    }
+}
@@ -0,0 +1,326 @@
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use color_eyre::eyre::Result;
+use log::{debug, info, warn};
+use rustyline::completion::{Completer, Pair};
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Editor, ExternalPrinter, Helper};
+use structopt::StructOpt;
+
+use crate::client::{self, Client};
+use crate::cmdline::{self, ClientCommand};
+use crate::common::{Event, Message, Request, RequestId, Response, TrackInfo, WSMsg};
+use crate::error::DoodleError;
+
+const PROMPT: &str = "doodle> ";
+
+/// Where the shell keeps its history, in the home directory
+const HISTORY_NAME: &str = ".musical-doodle-history";
+
+/// How often the shell looks for events while waiting at the prompt
+const EVENT_POLL: Duration = Duration::from_millis(100);
+
+/// Names to complete song arguments with, taken from the server's library
+type Completions = Arc<Mutex<BTreeSet<String>>>;
+
+struct ShellHelper {
+    completions: Completions,
+}
+
+impl Completer for ShellHelper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &rustyline::Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let (start, prefix) = current_word(&line[..pos]);
+        // The first word is the command, which isn't a song
+        if line[..start].trim().is_empty() {
+            return Ok((start, vec![]));
+        }
+
+        let prefix = prefix.to_lowercase();
+        let candidates = self
+            .completions
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|name| name.to_lowercase().starts_with(&prefix))
+            .map(|name| Pair {
+                display: name.clone(),
+                replacement: quote(name),
+            })
+            .collect();
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for ShellHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ShellHelper {}
+
+impl Validator for ShellHelper {}
+
+impl Helper for ShellHelper {}
+
+/// Find where the word being typed at the end of `line` starts,
+/// and what it is so far without any opening quote
+fn current_word(line: &str) -> (usize, &str) {
+    let mut start = 0;
+    let mut quote = None;
+    for (index, c) in line.char_indices() {
+        match quote {
+            Some(open) if c == open => quote = None,
+            Some(_) => {}
+            None if c == '"' || c == '\'' => {
+                quote = Some(c);
+                start = index;
+            }
+            None if c.is_whitespace() => start = index + c.len_utf8(),
+            None => {}
+        }
+    }
+
+    let word = &line[start..];
+    (start, word.trim_start_matches(['"', '\'']))
+}
+
+fn quote(name: &str) -> String {
+    if name.contains(|c: char| c.is_whitespace() || c == '"' || c == '\'' || c == '\\') {
+        shlex::try_quote(name).map_or_else(|_| name.to_owned(), |quoted| quoted.into_owned())
+    } else {
+        name.to_owned()
+    }
+}
+
+fn update_completions(completions: &Completions, tracks: &[TrackInfo]) {
+    let mut names = BTreeSet::new();
+    for track in tracks {
+        let tags = &track.tags;
+        names.extend(
+            [&tags.title, &tags.artist, &tags.album]
+                .into_iter()
+                .flatten()
+                .cloned(),
+        );
+        names.insert(track.display_name());
+        names.insert(track.path.to_string_lossy().into_owned());
+    }
+    *completions.lock().unwrap() = names;
+}
+
+fn format_event(event: &Event) -> String {
+    match event {
+        Event::LibraryChanged(summary) => format!(
+            "Library changed: {} added, {} updated, {} removed",
+            summary.added, summary.updated, summary.removed
+        ),
+        Event::TrackChanged(Some(track)) => format!("Now playing: {}", track.display_name()),
+        Event::TrackChanged(None) => "Nothing playing".to_owned(),
+        Event::PlaybackStateChanged(state) => format!("{:?}", state),
+        Event::QueueChanged { length, .. } => format!("Queue changed: {} tracks", length),
+        Event::VolumeChanged(volume) => format!("Volume: {}%", volume),
+        Event::MuteChanged(true) => "Muted".to_owned(),
+        Event::MuteChanged(false) => "Unmuted".to_owned(),
+        Event::SpeedChanged(speed) => client::format_speed(speed),
+        Event::Seeked(position) => format!("Seeked to {}", client::format_duration(*position)),
+    }
+}
+
+struct Shell<'a> {
+    client: &'a mut Client,
+    completions: Completions,
+    /// The pending request for the library's tracks
+    library_id: Option<RequestId>,
+}
+
+impl<'a> Shell<'a> {
+    /// Handle a message that isn't the response being waited for, if any.
+    /// Returns `false` once the server closed the connection.
+    fn on_message(&mut self, msg: WSMsg, print: &mut dyn FnMut(String)) -> Result<bool> {
+        match msg {
+            WSMsg::Message(Message::Event(event)) => {
+                if let Event::LibraryChanged(..) = event {
+                    self.library_id = Some(self.client.request(Request::Library)?);
+                }
+                print(format_event(&event));
+            }
+            WSMsg::Message(Message::Response { id, response }) if id == self.library_id => {
+                match response {
+                    Response::Library(tracks) => update_completions(&self.completions, &tracks),
+                    response => warn!("Unexpected response to the library request: {:?}", response),
+                }
+            }
+            WSMsg::Message(Message::Response { id, .. }) => {
+                debug!("Ignoring response to unrelated {:?}", id)
+            }
+            WSMsg::Close(code, reason) => {
+                info!("Connection closed: {:?} {}", code, reason);
+                print("The server closed the connection".to_owned());
+                return Ok(false);
+            }
+            rsp => Err(DoodleError::UnexpectedResponse(Box::new(rsp)))?,
+        }
+        Ok(true)
+    }
+
+    /// Run one line of input, returns `false` once the shell should exit
+    fn run_line(&mut self, line: &str) -> Result<bool> {
+        let words = match shlex::split(line) {
+            Some(words) => words,
+            None => {
+                println!("Unbalanced quotes");
+                return Ok(true);
+            }
+        };
+        match words.first().map(String::as_str) {
+            None => return Ok(true),
+            Some("exit" | "quit") => return Ok(false),
+            Some(_) => {}
+        }
+
+        let command =
+            match ClientCommand::from_iter_safe(std::iter::once(String::new()).chain(words)) {
+                Ok(command) => cmdline::Client { command },
+                Err(err) => {
+                    println!("{}", err.message);
+                    return Ok(true);
+                }
+            };
+        if let ClientCommand::Shell | ClientCommand::Tui | ClientCommand::Watch(_) = command.command
+        {
+            println!("Not available in the shell");
+            return Ok(true);
+        }
+
+        let request_id = self.client.request(client::make_request(&command)?)?;
+        loop {
+            match self.client.recv()? {
+                WSMsg::Message(Message::Response { id, response }) if id == Some(request_id) => {
+                    if let Err(err) = client::show_response(&command, response) {
+                        println!("{}", err);
+                    }
+                    // The server closes the connection once it shut down
+                    return Ok(!matches!(command.command, ClientCommand::Shutdown));
+                }
+                msg => {
+                    if !self.on_message(msg, &mut |line| println!("{}", line))? {
+                        return Ok(false);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Read commands from the terminal and run them over the client's connection,
+/// until end of input or `exit`
+pub(crate) fn main(client: &mut Client) -> Result<()> {
+    let completions = Completions::default();
+    let mut editor = Editor::<ShellHelper, DefaultHistory>::new()?;
+    editor.set_helper(Some(ShellHelper {
+        completions: completions.clone(),
+    }));
+    // Printing over the prompt only works on a terminal
+    let mut printer = editor
+        .create_external_printer()
+        .map_err(|err| debug!("Printing events without an external printer: {}", err))
+        .ok();
+    let mut print = move |line: String| match &mut printer {
+        Some(printer) => {
+            if let Err(err) = printer.print(line + "\n") {
+                warn!("Failed printing event: {}", err);
+            }
+        }
+        None => println!("{}", line),
+    };
+
+    let history = std::env::var_os("HOME").map(|home| PathBuf::from(home).join(HISTORY_NAME));
+    if let Some(path) = &history {
+        if let Err(err) = editor.load_history(path) {
+            debug!("No history loaded from {:?}: {}", path, err);
+        }
+    }
+
+    // The prompt blocks its thread, so lines are read on another thread
+    // while this one keeps printing the events that come in meanwhile.
+    // Each line is acknowledged once it ran, so the next prompt shows after its output.
+    let (line_tx, line_rx) = channel();
+    let (done_tx, done_rx) = channel::<()>();
+    let reader = thread::Builder::new()
+        .name("shell".to_owned())
+        .spawn(move || {
+            loop {
+                match editor.readline(PROMPT) {
+                    Ok(line) => {
+                        let _ = editor.add_history_entry(line.as_str());
+                        if line_tx.send(line).is_err() || done_rx.recv().is_err() {
+                            break;
+                        }
+                    }
+                    Err(ReadlineError::Interrupted) => continue,
+                    Err(ReadlineError::Eof) => break,
+                    Err(err) => {
+                        warn!("Failed reading input: {}", err);
+                        break;
+                    }
+                }
+            }
+
+            if let Some(path) = &history {
+                if let Err(err) = editor.save_history(path) {
+                    warn!("Failed saving history to {:?}: {}", path, err);
+                }
+            }
+        })?;
+
+    let mut shell = Shell {
+        library_id: Some(client.request(Request::Library)?),
+        client,
+        completions,
+    };
+
+    let result: Result<()> = (|| loop {
+        match line_rx.recv_timeout(EVENT_POLL) {
+            Ok(line) => {
+                if !shell.run_line(&line)? {
+                    return Ok(());
+                }
+                let _ = done_tx.send(());
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+
+        while let Some(msg) = shell.client.recv_timeout(Duration::ZERO)? {
+            if !shell.on_message(msg, &mut print)? {
+                print("Press Enter to exit".to_owned());
+                return Ok(());
+            }
+        }
+    })();
+
+    if let Err(err) = &result {
+        print(format!("{}\nPress Enter to exit", err));
+    }
+    drop(done_tx);
+    info!("Leaving the shell");
+    if reader.join().is_err() {
+        warn!("The shell's input thread panicked");
+    }
+    result
+}
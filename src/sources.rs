@@ -0,0 +1,628 @@
+use std::fs::File;
+use std::path::Path;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use color_eyre::eyre::Result;
+use log::warn;
+use rodio::cpal::FromSample;
+use rodio::{Sample, Source};
+use symphonia::core::audio::{SampleBuffer, SignalSpec};
+use symphonia::core::codecs::{self, DecoderOptions};
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::formats::{FormatOptions, FormatReader, SeekMode, SeekTo};
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+use symphonia::core::units::{Time, TimeBase};
+
+use crate::error::DoodleError;
+
+/// How many samples go by between updates of a `Position`
+const POSITION_UPDATE_SAMPLES: u32 = 1024;
+
+/// How long a `Ramped` source takes to go from silent to full volume
+const VOLUME_RAMP: Duration = Duration::from_millis(250);
+
+/// How long the overlapping windows of a `Stretched` source are.
+/// Long enough to hold a couple of voice periods, short enough not to echo.
+const STRETCH_WINDOW: Duration = Duration::from_millis(40);
+/// How far either way a `Stretched` source looks for the window that best continues the last one
+const STRETCH_TOLERANCE: Duration = Duration::from_millis(10);
+/// Only every this many frames are compared when looking for the best window
+const STRETCH_COMPARE_STEP: usize = 4;
+
+/// How many packets in a row may fail to decode before a track is given up on
+const MAX_DECODE_ERRORS: usize = 3;
+
+fn to_duration(time: Time) -> Duration {
+    Duration::from_secs(time.seconds) + Duration::from_secs_f64(time.frac)
+}
+
+/// How far into its track a `Tracked` source is, readable from other threads
+#[derive(Debug, Clone, Default)]
+pub struct Position(Arc<AtomicU64>);
+
+impl Position {
+    pub fn get(&self) -> Duration {
+        Duration::from_nanos(self.0.load(Ordering::Relaxed))
+    }
+
+    fn set(&self, position: Duration) {
+        self.0.store(position.as_nanos() as u64, Ordering::Relaxed);
+    }
+}
+
+/// Keeps a `Position` updated with the amount of the inner source that was played.
+///
+/// Since this counts the samples taken out of the track itself,
+/// the position is in track time no matter the playback speed.
+pub struct Tracked<S> {
+    inner: S,
+    position: Position,
+    elapsed: Duration,
+    unpublished: u32,
+}
+
+impl<S: Source> Tracked<S>
+where
+    S::Item: Sample,
+{
+    /// Track a source that starts `start` into its track, e.g. after seeking
+    pub fn new(inner: S, position: Position, start: Duration) -> Self {
+        position.set(start);
+        Self {
+            inner,
+            position,
+            elapsed: start,
+            unpublished: 0,
+        }
+    }
+
+    fn publish(&mut self) {
+        let samples_per_sec = self.inner.sample_rate() as u64 * self.inner.channels() as u64;
+        if let Some(nanos) = (self.unpublished as u64 * 1_000_000_000).checked_div(samples_per_sec)
+        {
+            self.elapsed += Duration::from_nanos(nanos);
+        }
+        self.unpublished = 0;
+        self.position.set(self.elapsed);
+    }
+}
+
+impl<S: Source> Iterator for Tracked<S>
+where
+    S::Item: Sample,
+{
+    type Item = S::Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let sample = self.inner.next();
+        match sample {
+            Some(_) => {
+                self.unpublished += 1;
+                if self.unpublished >= POSITION_UPDATE_SAMPLES {
+                    self.publish();
+                }
+            }
+            None => self.publish(),
+        }
+        sample
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<S: Source> Source for Tracked<S>
+where
+    S::Item: Sample,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        self.inner.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.inner.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.inner.total_duration()
+    }
+}
+
+/// A factor sources play at, such as their volume, shared with the player thread
+#[derive(Debug, Clone)]
+pub struct Factor(Arc<AtomicU32>);
+
+impl Factor {
+    pub fn new(factor: f32) -> Self {
+        Self(Arc::new(AtomicU32::new(factor.to_bits())))
+    }
+
+    pub fn get(&self) -> f32 {
+        f32::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    pub fn set(&self, factor: f32) {
+        self.0.store(factor.to_bits(), Ordering::Relaxed);
+    }
+}
+
+/// Plays the inner source at a volume `Factor`, fading into changes of it
+/// rather than jumping so a big change doesn't blast the room
+pub struct Ramped<S> {
+    inner: S,
+    volume: Factor,
+    current: f32,
+}
+
+impl<S: Source> Ramped<S>
+where
+    S::Item: Sample,
+{
+    pub fn new(inner: S, volume: Factor) -> Self {
+        Self {
+            inner,
+            current: volume.get(),
+            volume,
+        }
+    }
+}
+
+impl<S: Source> Iterator for Ramped<S>
+where
+    S::Item: Sample,
+{
+    type Item = S::Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let target = self.volume.get();
+        if self.current != target {
+            let samples_per_sec = self.inner.sample_rate() as f32 * self.inner.channels() as f32;
+            let step = 1.0 / (VOLUME_RAMP.as_secs_f32() * samples_per_sec).max(1.0);
+            self.current = if self.current < target {
+                (self.current + step).min(target)
+            } else {
+                (self.current - step).max(target)
+            };
+        }
+        Some(self.inner.next()?.amplify(self.current))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<S: Source> Source for Ramped<S>
+where
+    S::Item: Sample,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        self.inner.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.inner.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.inner.total_duration()
+    }
+}
+
+/// Plays the inner source at a speed `Factor` without changing its pitch.
+///
+/// This uses WSOLA: overlapping windows of the input are taken a speed-scaled distance apart
+/// and added back together a fixed distance apart, each window shifted a little
+/// so its waveform lines up with the previous one.
+/// The source is passed through untouched until its speed first changes.
+pub struct Stretched<S> {
+    inner: S,
+    speed: Factor,
+    channels: usize,
+    stretching: bool,
+    /// Hann window, its length is the window length in frames
+    window: Vec<f32>,
+    /// How far apart windows are added to the output, in frames
+    hop: usize,
+    /// How far windows may be shifted, in frames
+    tolerance: usize,
+    /// Interleaved input samples still needed
+    input: Vec<f32>,
+    /// How many of the input samples are real rather than padding after the inner source ended
+    input_len: Option<usize>,
+    /// Where the next window would be taken from without shifting, in frames into `input`
+    position: f64,
+    /// Where the previous window would have carried on, in frames into `input`
+    continuation: Option<usize>,
+    /// The windows added up so far, the first `hop` frames of which are complete after each window
+    mixed: Vec<f32>,
+    ready: Vec<f32>,
+    ready_index: usize,
+    finished: bool,
+}
+
+impl<S: Source> Stretched<S>
+where
+    S::Item: Sample,
+    f32: FromSample<S::Item>,
+{
+    pub fn new(inner: S, speed: Factor) -> Self {
+        let channels = inner.channels().max(1) as usize;
+        let frames = |duration: Duration| {
+            (duration.as_secs_f64() * inner.sample_rate() as f64).round() as usize
+        };
+        let hop = frames(STRETCH_WINDOW / 2).max(1);
+        let window = (0..hop * 2)
+            .map(|frame| {
+                let phase = std::f32::consts::PI * frame as f32 / hop as f32;
+                0.5 - 0.5 * phase.cos()
+            })
+            .collect();
+
+        Self {
+            channels,
+            stretching: false,
+            window,
+            hop,
+            tolerance: frames(STRETCH_TOLERANCE),
+            input: vec![],
+            input_len: None,
+            position: 0.0,
+            continuation: None,
+            mixed: vec![0.0; hop * 2 * channels],
+            ready: vec![],
+            ready_index: 0,
+            finished: false,
+            inner,
+            speed,
+        }
+    }
+
+    /// Read the inner source until there are `frames` frames of input,
+    /// padding with silence once it ended
+    fn fill_input(&mut self, frames: usize) {
+        let wanted = frames * self.channels;
+        while self.input.len() < wanted {
+            match self.input_len {
+                Some(_) => self.input.push(0.0),
+                None => match self.inner.next() {
+                    Some(sample) => self.input.push(f32::from_sample_(sample)),
+                    None => self.input_len = Some(self.input.len()),
+                },
+            }
+        }
+    }
+
+    /// Find the window start between `from` and `to` that sounds most like `natural`,
+    /// which is where the previous window would have carried on
+    fn best_match(&self, natural: usize, from: usize, to: usize) -> usize {
+        let mono: Vec<f32> = self
+            .input
+            .chunks(self.channels)
+            .map(|frame| frame.iter().sum())
+            .collect();
+
+        let mut best = (f32::MIN, from);
+        for start in from..=to {
+            let (mut correlation, mut energy) = (0.0, 0.0);
+            for offset in (0..self.hop).step_by(STRETCH_COMPARE_STEP) {
+                let candidate = mono[start + offset];
+                correlation += mono[natural + offset] * candidate;
+                energy += candidate * candidate;
+            }
+            let score = correlation / energy.sqrt().max(f32::EPSILON);
+            if score > best.0 {
+                best = (score, start);
+            }
+        }
+        best.1
+    }
+
+    /// Add the next window to the output, making the next `hop` frames ready
+    fn stretch_window(&mut self) {
+        let length = self.window.len();
+        let nominal = self.position.round() as usize;
+        let (from, to) = (
+            nominal.saturating_sub(self.tolerance),
+            nominal + self.tolerance,
+        );
+        let natural = self.continuation;
+        self.fill_input(to.max(natural.unwrap_or(0)) + length);
+
+        if let Some(input_len) = self.input_len {
+            if from * self.channels >= input_len {
+                // Whatever is left is the fading tail of the last window
+                self.ready = std::mem::take(&mut self.mixed);
+                self.ready_index = 0;
+                self.finished = true;
+                return;
+            }
+        }
+
+        let start = match natural {
+            Some(natural) => self.best_match(natural, from, to),
+            None => nominal,
+        };
+        let channels = self.channels;
+        let input = &self.input[start * channels..(start + length) * channels];
+        for (index, (mixed, sample)) in self.mixed.iter_mut().zip(input).enumerate() {
+            let frame = index / channels;
+            // Nothing overlaps the start of the first window, so it's not faded in
+            let weight = match natural {
+                None if frame < self.hop => 1.0,
+                _ => self.window[frame],
+            };
+            *mixed += weight * sample;
+        }
+
+        let done = self.hop * channels;
+        self.ready.clear();
+        self.ready.extend(self.mixed.drain(..done));
+        self.ready_index = 0;
+        self.mixed.resize(length * channels, 0.0);
+
+        self.position += self.hop as f64 * self.speed.get() as f64;
+
+        // Drop the input no later window can start from
+        let unused = (self.position as usize)
+            .saturating_sub(self.tolerance)
+            .min(start + self.hop);
+        self.input.drain(..unused * channels);
+        self.input_len = self
+            .input_len
+            .map(|len| len.saturating_sub(unused * channels));
+        self.position -= unused as f64;
+        self.continuation = Some(start + self.hop - unused);
+    }
+}
+
+impl<S: Source> Iterator for Stretched<S>
+where
+    S::Item: Sample,
+    f32: FromSample<S::Item>,
+{
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if !self.stretching {
+            if self.speed.get() == 1.0 {
+                return self.inner.next().map(f32::from_sample_);
+            }
+            self.stretching = true;
+        }
+
+        while self.ready_index >= self.ready.len() {
+            if self.finished {
+                return None;
+            }
+            self.stretch_window();
+        }
+        let sample = self.ready[self.ready_index];
+        self.ready_index += 1;
+        Some(sample)
+    }
+}
+
+impl<S: Source> Source for Stretched<S>
+where
+    S::Item: Sample,
+    f32: FromSample<S::Item>,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        if self.stretching {
+            None
+        } else {
+            self.inner.current_frame_len()
+        }
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels as u16
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/// Decodes a track with symphonia.
+///
+/// Unlike `rodio::Decoder`, this can seek within the track before it's played.
+pub struct Decoded {
+    format: Box<dyn FormatReader>,
+    decoder: Box<dyn codecs::Decoder>,
+    track_id: u32,
+    time_base: Option<TimeBase>,
+    duration: Option<Duration>,
+    spec: SignalSpec,
+    buffer: Option<SampleBuffer<i16>>,
+    offset: usize,
+    /// Where the next sample should come from after a seek, in track time
+    skip_to: Option<Duration>,
+}
+
+impl Decoded {
+    pub fn open(path: &Path) -> Result<Self> {
+        let stream = MediaSourceStream::new(Box::new(File::open(path)?), Default::default());
+
+        let mut hint = Hint::new();
+        if let Some(extension) = path.extension().and_then(|ext| ext.to_str()) {
+            hint.with_extension(extension);
+        }
+
+        let format_options = FormatOptions {
+            enable_gapless: true,
+            ..Default::default()
+        };
+        let probed = symphonia::default::get_probe().format(
+            &hint,
+            stream,
+            &format_options,
+            &MetadataOptions::default(),
+        )?;
+
+        let track = probed
+            .format
+            .default_track()
+            .ok_or_else(|| DoodleError::Generic(format!("no audio track in {:?}", path)))?;
+        let params = &track.codec_params;
+        let decoder = symphonia::default::get_codecs().make(params, &DecoderOptions::default())?;
+        let duration = params
+            .time_base
+            .zip(params.n_frames)
+            .map(|(time_base, frames)| to_duration(time_base.calc_time(frames)));
+
+        let mut decoded = Self {
+            track_id: track.id,
+            time_base: params.time_base,
+            duration,
+            spec: SignalSpec::new(
+                params.sample_rate.unwrap_or_default(),
+                params.channels.unwrap_or_default(),
+            ),
+            format: probed.format,
+            decoder,
+            buffer: None,
+            offset: 0,
+            skip_to: None,
+        };
+        // The channels and rate are only known for sure once a packet was decoded
+        decoded.decode_packet()?;
+        Ok(decoded)
+    }
+
+    /// Move to `to` within the track, clamped to its length.
+    /// Returns where playback will actually resume.
+    pub fn seek(&mut self, to: Duration) -> Result<Duration> {
+        let to = match self.duration {
+            Some(duration) => to.min(duration),
+            None => to,
+        };
+        let seeked = self.format.seek(
+            SeekMode::Accurate,
+            SeekTo::Time {
+                time: Time::from(to.as_secs_f64()),
+                track_id: Some(self.track_id),
+            },
+        )?;
+        self.decoder.reset();
+
+        // The reader lands on a packet at or before the requested time,
+        // the samples up to it are dropped as they're decoded
+        let reached = match self.time_base {
+            Some(time_base) => to_duration(time_base.calc_time(seeked.required_ts)),
+            None => to,
+        };
+        self.skip_to = Some(reached);
+        self.decode_packet()?;
+        Ok(reached)
+    }
+
+    /// Decode the next packet of the track into the buffer,
+    /// returns `false` and leaves it empty at the end of the track
+    fn decode_packet(&mut self) -> Result<bool> {
+        self.buffer = None;
+        let mut errors = 0;
+        loop {
+            let packet = match self.format.next_packet() {
+                Ok(packet) => packet,
+                Err(SymphoniaError::IoError(err))
+                    if err.kind() == std::io::ErrorKind::UnexpectedEof =>
+                {
+                    return Ok(false)
+                }
+                Err(err) => Err(err)?,
+            };
+            if packet.track_id() != self.track_id {
+                continue;
+            }
+
+            let decoded = match self.decoder.decode(&packet) {
+                Ok(decoded) => decoded,
+                Err(SymphoniaError::DecodeError(_)) if errors < MAX_DECODE_ERRORS => {
+                    errors += 1;
+                    continue;
+                }
+                Err(err) => Err(err)?,
+            };
+            self.spec = *decoded.spec();
+            let mut buffer = SampleBuffer::new(decoded.capacity() as u64, self.spec);
+            buffer.copy_interleaved_ref(decoded);
+            self.offset = 0;
+
+            if let (Some(skip_to), Some(time_base)) = (self.skip_to, self.time_base) {
+                let start = to_duration(time_base.calc_time(packet.ts));
+                let end = to_duration(time_base.calc_time(packet.ts + packet.dur));
+                if end <= skip_to {
+                    continue;
+                }
+                let frames = ((skip_to.saturating_sub(start)).as_secs_f64() * self.spec.rate as f64)
+                    as usize;
+                self.offset = frames * self.spec.channels.count();
+            }
+            if self.offset >= buffer.len() {
+                continue;
+            }
+            self.skip_to = None;
+            self.buffer = Some(buffer);
+            return Ok(true);
+        }
+    }
+}
+
+impl Iterator for Decoded {
+    type Item = i16;
+
+    fn next(&mut self) -> Option<i16> {
+        let buffer = self.buffer.as_ref()?;
+        let sample = buffer.samples()[self.offset];
+        self.offset += 1;
+
+        // Decode ahead so the frame length is known before the next sample is asked for
+        if self.offset == buffer.len() {
+            if let Err(err) = self.decode_packet() {
+                warn!("Stopped decoding: {}", err);
+            }
+        }
+        Some(sample)
+    }
+}
+
+impl Source for Decoded {
+    fn current_frame_len(&self) -> Option<usize> {
+        Some(
+            self.buffer
+                .as_ref()
+                .map_or(0, |buffer| buffer.len() - self.offset),
+        )
+    }
+
+    fn channels(&self) -> u16 {
+        self.spec.channels.count() as u16
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.spec.rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.duration
+    }
+}
@@ -0,0 +1,564 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::Stdout;
+use std::time::{Duration, Instant};
+
+use color_eyre::eyre::Result;
+use crossterm::event::{self, Event as TermEvent, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::terminal::{self, EnterAlternateScreen, LeaveAlternateScreen};
+use log::{debug, warn};
+use ratatui::backend::CrosstermBackend;
+use ratatui::layout::{Constraint, Direction, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::widgets::{Block, Borders, Gauge, List, ListItem, ListState, Paragraph};
+use ratatui::{Frame, Terminal};
+
+use crate::client::{self, Client};
+use crate::common::{
+    self, Event, Message, Music, QueueListResp, Request, RequestId, Response, SeekReq, StatusResp,
+    TrackInfo, VolumeReq, WSMsg,
+};
+use crate::error::DoodleError;
+
+/// How long to wait for a key press before redrawing
+const FRAME: Duration = Duration::from_millis(100);
+
+const UNKNOWN_ARTIST: &str = "Unknown artist";
+const UNKNOWN_ALBUM: &str = "Unknown album";
+
+const HELP: &str = "Tab: switch pane  Up/Down: select  Enter: play  a: add to queue  \
+                    Space: pause  n/p: next/previous  Left/Right: seek  +/-: volume  m: mute  [/]: speed  q: quit";
+
+/// How much the volume keys change the volume by, in percent
+const VOLUME_STEP: u8 = 5;
+
+/// How much the speed keys change the speed by
+const SPEED_STEP: f32 = 0.1;
+
+/// How far the arrow keys seek
+const SEEK_STEP: Duration = Duration::from_secs(10);
+
+struct Album {
+    name: String,
+    tracks: Vec<TrackInfo>,
+}
+
+struct Artist {
+    name: String,
+    albums: Vec<Album>,
+}
+
+/// Group the library's tracks by artist and album, in album order
+fn group_tracks(tracks: Vec<TrackInfo>) -> Vec<Artist> {
+    let mut artists: BTreeMap<String, BTreeMap<String, Vec<TrackInfo>>> = BTreeMap::new();
+    for track in tracks {
+        let artist = track.tags.artist.clone();
+        let album = track.tags.album.clone();
+        artists
+            .entry(artist.unwrap_or_else(|| UNKNOWN_ARTIST.to_owned()))
+            .or_default()
+            .entry(album.unwrap_or_else(|| UNKNOWN_ALBUM.to_owned()))
+            .or_default()
+            .push(track);
+    }
+
+    artists
+        .into_iter()
+        .map(|(name, albums)| Artist {
+            name,
+            albums: albums
+                .into_iter()
+                .map(|(name, mut tracks)| {
+                    tracks.sort_by(|a, b| {
+                        (a.tags.disc, a.tags.track_number, &a.path).cmp(&(
+                            b.tags.disc,
+                            b.tags.track_number,
+                            &b.path,
+                        ))
+                    });
+                    Album { name, tracks }
+                })
+                .collect(),
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Pane {
+    Artists,
+    Albums,
+    Tracks,
+    Queue,
+}
+
+impl Pane {
+    fn next(self) -> Self {
+        match self {
+            Self::Artists => Self::Albums,
+            Self::Albums => Self::Tracks,
+            Self::Tracks => Self::Queue,
+            Self::Queue => Self::Artists,
+        }
+    }
+}
+
+/// What a request sent by the UI was for
+#[derive(Debug)]
+enum Pending {
+    Status,
+    Queue,
+    Library,
+    /// A command from the user, the error responses of which are shown
+    Command,
+}
+
+struct App<'a> {
+    client: &'a mut Client,
+    pending: HashMap<RequestId, Pending>,
+    artists: Vec<Artist>,
+    artist: ListState,
+    album: ListState,
+    track: ListState,
+    queue: QueueListResp,
+    queue_state: ListState,
+    status: Option<(StatusResp, Instant)>,
+    focus: Pane,
+    /// Shown instead of the help line until the next key press
+    message: Option<String>,
+    quit: bool,
+}
+
+impl<'a> App<'a> {
+    fn new(client: &'a mut Client) -> Self {
+        Self {
+            client,
+            pending: HashMap::new(),
+            artists: vec![],
+            artist: ListState::default(),
+            album: ListState::default(),
+            track: ListState::default(),
+            queue: QueueListResp {
+                tracks: vec![],
+                position: None,
+            },
+            queue_state: ListState::default(),
+            status: None,
+            focus: Pane::Artists,
+            message: None,
+            quit: false,
+        }
+    }
+
+    fn request(&mut self, request: Request, pending: Pending) -> Result<()> {
+        let id = self.client.request(request)?;
+        self.pending.insert(id, pending);
+        Ok(())
+    }
+
+    fn selected_artist(&self) -> Option<&Artist> {
+        self.artists.get(self.artist.selected()?)
+    }
+
+    fn selected_album(&self) -> Option<&Album> {
+        self.selected_artist()?.albums.get(self.album.selected()?)
+    }
+
+    /// The tracks under the selection in the focused library pane
+    fn selected_tracks(&self) -> Vec<TrackInfo> {
+        match self.focus {
+            Pane::Artists => self
+                .selected_artist()
+                .map(|artist| {
+                    artist
+                        .albums
+                        .iter()
+                        .flat_map(|album| album.tracks.iter().cloned())
+                        .collect()
+                })
+                .unwrap_or_default(),
+            Pane::Albums => self
+                .selected_album()
+                .map(|album| album.tracks.clone())
+                .unwrap_or_default(),
+            Pane::Tracks => self
+                .selected_album()
+                .and_then(|album| album.tracks.get(self.track.selected()?))
+                .cloned()
+                .into_iter()
+                .collect(),
+            Pane::Queue => vec![],
+        }
+    }
+
+    fn selection_music(&self) -> Option<Music> {
+        let tracks = self.selected_tracks();
+        if tracks.is_empty() {
+            return None;
+        }
+        let paths = tracks
+            .iter()
+            .map(|track| track.path.to_string_lossy().into_owned())
+            .collect();
+        Some(Music::Songs(paths))
+    }
+
+    /// Move the selection of the focused pane, resetting the panes below it
+    fn move_selection(&mut self, delta: isize) {
+        let (len, state) = match self.focus {
+            Pane::Artists => (self.artists.len(), &mut self.artist),
+            Pane::Albums => (
+                self.artists
+                    .get(self.artist.selected().unwrap_or(0))
+                    .map_or(0, |artist| artist.albums.len()),
+                &mut self.album,
+            ),
+            Pane::Tracks => (
+                self.selected_album().map_or(0, |album| album.tracks.len()),
+                &mut self.track,
+            ),
+            Pane::Queue => (self.queue.tracks.len(), &mut self.queue_state),
+        };
+        if len == 0 {
+            return;
+        }
+
+        let selected = state.selected().unwrap_or(0) as isize + delta;
+        state.select(Some(selected.clamp(0, len as isize - 1) as usize));
+
+        match self.focus {
+            Pane::Artists => {
+                self.album.select(Some(0));
+                self.track.select(Some(0));
+            }
+            Pane::Albums => self.track.select(Some(0)),
+            Pane::Tracks | Pane::Queue => {}
+        }
+    }
+
+    /// Speed up or slow down from the last known speed, keeping the pitch setting
+    fn change_speed(&mut self, step: f32) -> Result<()> {
+        let mut speed = self
+            .status
+            .as_ref()
+            .map(|(status, _)| status.speed)
+            .unwrap_or_default();
+        // Round so repeated steps land on tenths rather than drifting
+        speed.speed = ((speed.speed + step) * 10.0).round() / 10.0;
+        self.request(Request::SetSpeed(speed), Pending::Command)
+    }
+
+    fn on_key(&mut self, key: KeyEvent) -> Result<()> {
+        self.message = None;
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => self.quit = true,
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => self.quit = true,
+            KeyCode::Tab => self.focus = self.focus.next(),
+            KeyCode::Up => self.move_selection(-1),
+            KeyCode::Down => self.move_selection(1),
+            KeyCode::PageUp => self.move_selection(-10),
+            KeyCode::PageDown => self.move_selection(10),
+            KeyCode::Char(' ') => self.request(Request::TogglePause, Pending::Command)?,
+            KeyCode::Char('n') => self.request(Request::Next, Pending::Command)?,
+            KeyCode::Char('p') => self.request(Request::Previous, Pending::Command)?,
+            KeyCode::Char('+' | '=') => {
+                let volume = VolumeReq::Up(VOLUME_STEP);
+                self.request(Request::SetVolume(volume), Pending::Command)?
+            }
+            KeyCode::Char('-') => {
+                let volume = VolumeReq::Down(VOLUME_STEP);
+                self.request(Request::SetVolume(volume), Pending::Command)?
+            }
+            KeyCode::Char('[') => self.change_speed(-SPEED_STEP)?,
+            KeyCode::Char(']') => self.change_speed(SPEED_STEP)?,
+            KeyCode::Char('m') => self.request(Request::ToggleMute, Pending::Command)?,
+            KeyCode::Left => {
+                let seek = SeekReq::Backward(SEEK_STEP);
+                self.request(Request::Seek(seek), Pending::Command)?
+            }
+            KeyCode::Right => {
+                let seek = SeekReq::Forward(SEEK_STEP);
+                self.request(Request::Seek(seek), Pending::Command)?
+            }
+            KeyCode::Enter if self.focus == Pane::Queue => {
+                if let Some(index) = self.queue_state.selected() {
+                    self.request(Request::PlayIndex(index), Pending::Command)?;
+                }
+            }
+            KeyCode::Enter => {
+                if let Some(music) = self.selection_music() {
+                    let play = common::PlayReq {
+                        music: Some(music),
+                        shuffled: false,
+                        repeat: false,
+                    };
+                    self.request(Request::Play(play), Pending::Command)?;
+                }
+            }
+            KeyCode::Char('a') => {
+                if let Some(music) = self.selection_music() {
+                    let queue = common::QueueReq {
+                        music,
+                        shuffled: false,
+                    };
+                    self.request(Request::Queue(queue), Pending::Command)?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn on_message(&mut self, msg: WSMsg) -> Result<()> {
+        match msg {
+            WSMsg::Message(Message::Event(event)) => match event {
+                Event::LibraryChanged(..) => self.request(Request::Library, Pending::Library)?,
+                Event::QueueChanged { .. } => self.request(Request::ListQueue, Pending::Queue)?,
+                Event::TrackChanged(..)
+                | Event::PlaybackStateChanged(..)
+                | Event::VolumeChanged(..)
+                | Event::MuteChanged(..)
+                | Event::SpeedChanged(..)
+                | Event::Seeked(..) => {
+                    self.request(Request::Status, Pending::Status)?;
+                    // The queue's current track moved along
+                    self.request(Request::ListQueue, Pending::Queue)?;
+                }
+            },
+            WSMsg::Message(Message::Response { id, response }) => {
+                let pending = id.and_then(|id| self.pending.remove(&id));
+                match (pending, response) {
+                    (_, Response::Error(err)) => self.message = Some(err.to_string()),
+                    (Some(Pending::Status), Response::Status(status)) => {
+                        self.status = Some((status, Instant::now()))
+                    }
+                    (Some(Pending::Queue), Response::QueueList(queue)) => {
+                        if self.queue_state.selected().is_none() {
+                            self.queue_state.select(queue.position);
+                        }
+                        self.queue = queue;
+                    }
+                    (Some(Pending::Library), Response::Library(tracks)) => {
+                        self.artists = group_tracks(tracks);
+                        if self.artist.selected().is_none() {
+                            self.artist.select(Some(0));
+                            self.album.select(Some(0));
+                            self.track.select(Some(0));
+                        }
+                    }
+                    (Some(Pending::Command), _) => {}
+                    (pending, response) => {
+                        debug!("Ignoring {:?} response {:?}", pending, response)
+                    }
+                }
+            }
+            WSMsg::Close(code, reason) => Err(DoodleError::Generic(format!(
+                "the server closed the connection: {:?} {}",
+                code, reason
+            )))?,
+            rsp => Err(DoodleError::UnexpectedResponse(Box::new(rsp)))?,
+        }
+        Ok(())
+    }
+
+    fn pane_block(&self, title: &str, pane: Pane) -> Block<'static> {
+        let style = if self.focus == pane {
+            Style::default().fg(Color::Yellow)
+        } else {
+            Style::default()
+        };
+        Block::default()
+            .borders(Borders::ALL)
+            .border_style(style)
+            .title(title.to_owned())
+    }
+
+    fn draw_list(
+        &mut self,
+        frame: &mut Frame,
+        area: Rect,
+        pane: Pane,
+        title: &str,
+        items: Vec<ListItem<'static>>,
+    ) {
+        let list = List::new(items)
+            .block(self.pane_block(title, pane))
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED))
+            .highlight_symbol("> ");
+        let state = match pane {
+            Pane::Artists => &mut self.artist,
+            Pane::Albums => &mut self.album,
+            Pane::Tracks => &mut self.track,
+            Pane::Queue => &mut self.queue_state,
+        };
+        frame.render_stateful_widget(list, area, state);
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let rows = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([
+                Constraint::Min(5),
+                Constraint::Length(3),
+                Constraint::Length(1),
+            ])
+            .split(frame.size());
+        let panes = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([
+                Constraint::Percentage(20),
+                Constraint::Percentage(20),
+                Constraint::Percentage(30),
+                Constraint::Percentage(30),
+            ])
+            .split(rows[0]);
+
+        let artists = self
+            .artists
+            .iter()
+            .map(|artist| ListItem::new(artist.name.clone()))
+            .collect();
+        let albums = self
+            .selected_artist()
+            .map(|artist| {
+                artist
+                    .albums
+                    .iter()
+                    .map(|album| ListItem::new(album.name.clone()))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let tracks = self
+            .selected_album()
+            .map(|album| {
+                album
+                    .tracks
+                    .iter()
+                    .map(|track| {
+                        let title = track.tags.title.clone().unwrap_or_else(|| {
+                            track
+                                .path
+                                .file_name()
+                                .unwrap_or_default()
+                                .to_string_lossy()
+                                .into_owned()
+                        });
+                        ListItem::new(title)
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        let queue = self
+            .queue
+            .tracks
+            .iter()
+            .enumerate()
+            .map(|(index, track)| {
+                let item = ListItem::new(track.display_name());
+                if Some(index) == self.queue.position {
+                    item.style(Style::default().add_modifier(Modifier::BOLD))
+                } else {
+                    item
+                }
+            })
+            .collect();
+
+        self.draw_list(frame, panes[0], Pane::Artists, "Artists", artists);
+        self.draw_list(frame, panes[1], Pane::Albums, "Albums", albums);
+        self.draw_list(frame, panes[2], Pane::Tracks, "Tracks", tracks);
+        self.draw_list(frame, panes[3], Pane::Queue, "Queue", queue);
+
+        self.draw_now_playing(frame, rows[1]);
+
+        let bottom = match &self.message {
+            Some(message) => Paragraph::new(message.clone()).style(Style::default().fg(Color::Red)),
+            None => Paragraph::new(HELP),
+        };
+        frame.render_widget(bottom, rows[2]);
+    }
+
+    fn draw_now_playing(&self, frame: &mut Frame, area: Rect) {
+        let status = match &self.status {
+            Some((status, received)) => client::status_since(status, *received),
+            None => {
+                let block = Block::default().borders(Borders::ALL).title("Now playing");
+                frame.render_widget(Paragraph::new("...").block(block), area);
+                return;
+            }
+        };
+
+        let title = format!(
+            "Volume {}%{} | speed {}x | repeat {} | shuffle {}",
+            status.volume,
+            if status.muted { " (muted)" } else { "" },
+            status.speed.speed,
+            if status.repeat { "on" } else { "off" },
+            if status.shuffled { "on" } else { "off" }
+        );
+        let duration = status.track.as_ref().and_then(|track| track.duration);
+        let ratio = match (status.position, duration) {
+            (Some(position), Some(duration)) if !duration.is_zero() => {
+                (position.as_secs_f64() / duration.as_secs_f64()).clamp(0.0, 1.0)
+            }
+            _ => 0.0,
+        };
+        let gauge = Gauge::default()
+            .block(Block::default().borders(Borders::ALL).title(title))
+            .gauge_style(Style::default().fg(Color::Cyan))
+            .ratio(ratio)
+            .label(client::format_now_playing(&status));
+        frame.render_widget(gauge, area);
+    }
+}
+
+/// Puts the terminal back the way it was, even when leaving on an error
+struct TerminalGuard(Terminal<CrosstermBackend<Stdout>>);
+
+impl TerminalGuard {
+    fn new() -> Result<Self> {
+        terminal::enable_raw_mode()?;
+        let mut stdout = std::io::stdout();
+        if let Err(err) = crossterm::execute!(stdout, EnterAlternateScreen) {
+            let _ = terminal::disable_raw_mode();
+            Err(err)?;
+        }
+        Ok(Self(Terminal::new(CrosstermBackend::new(stdout))?))
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        if let Err(err) = terminal::disable_raw_mode() {
+            warn!("Failed restoring the terminal: {}", err);
+        }
+        if let Err(err) = crossterm::execute!(self.0.backend_mut(), LeaveAlternateScreen) {
+            warn!("Failed restoring the terminal: {}", err);
+        }
+        let _ = self.0.show_cursor();
+    }
+}
+
+/// Run the full-screen UI over the client's connection, until the user quits
+pub(crate) fn main(client: &mut Client) -> Result<()> {
+    let mut app = App::new(client);
+    app.request(Request::Library, Pending::Library)?;
+    app.request(Request::ListQueue, Pending::Queue)?;
+    app.request(Request::Status, Pending::Status)?;
+
+    let mut terminal = TerminalGuard::new()?;
+    while !app.quit {
+        terminal.0.draw(|frame| app.draw(frame))?;
+
+        if event::poll(FRAME)? {
+            if let TermEvent::Key(key) = event::read()? {
+                if key.kind == KeyEventKind::Press {
+                    app.on_key(key)?;
+                }
+            }
+        }
+
+        while let Some(msg) = app.client.recv_timeout(Duration::ZERO)? {
+            app.on_message(msg)?;
+        }
+    }
+
+    Ok(())
+}